@@ -1,11 +1,12 @@
 // this file is part of arpfisch. For copyright and licensing details, see main.rs
 
-use crate::arpeggiator::{ArpeggiatorInstance, ClockMode};
-use crate::driver::DriverFrame;
+use crate::arpeggiator::{faders, ArpeggiatorInstance, ClockMode, MixerState, RepeatMode};
+use crate::driver::{DriverFrame, TransportPosition};
 use crate::grid_controllers::launchpad_x::LaunchpadX;
 use crate::grid_controllers::GridController;
-use crate::gui::GuiController;
-use crate::midi::{Channel, MidiEvent};
+use crate::gui::{DrawContext, GuiController, InputContext};
+use crate::midi::{Channel, MidiEvent, Note, DEFAULT_RELEASE_VELOCITY};
+use crate::osc::{OscReply, OscRequest, Parameter, Transport, Value};
 use heapless;
 use serde::{Deserialize, Serialize};
 
@@ -47,17 +48,33 @@ pub struct ArpApplication {
 	next_midiclock_to_send: u64,
 	old_routing_matrix: Vec<Vec<bool>>,
 	restart_transport_pending: bool,
+	stop_transport_pending: bool,
+	continue_transport_pending: bool,
+	/// Whether the transport is running. While stopped, clocks are still passed
+	/// through, but do not advance the arpeggiators.
+	playing: bool,
+	/// Index of the next MIDI clock to generate when following the JACK transport
+	next_transport_clock: u64,
+	/// Where the JACK transport should be at the next frame, in MIDI clocks, if it
+	/// wasn't relocated
+	expected_transport_position: f64,
 
 	save_buffer_receive: ringbuf::Consumer<Box<SaveBuffer>>,
 	save_buffer_return: ringbuf::Producer<Box<SaveBuffer>>,
 
+	osc_requests: Option<ringbuf::Consumer<OscRequest>>,
+	osc_replies: Option<ringbuf::Producer<OscReply>>,
+
 	serializable: ArpApplicationSerializable
 }
 
 #[derive(Serialize, Deserialize, Clone)]
 struct ArpApplicationSerializable {
-	in_channel: Channel,
-	out_channel: Channel,
+	/// Only present in old save files, before the channels were set per arpeggiator
+	#[serde(default, skip_serializing)]
+	in_channel: Option<Channel>,
+	#[serde(default, skip_serializing)]
+	out_channel: Option<Channel>,
 
 	time_between_midiclocks: u64,
 	clock_mode: ClockMode,
@@ -74,7 +91,7 @@ impl ArpApplication {
 		save_buffer_receive: ringbuf::Consumer<Box<SaveBuffer>>,
 		save_buffer_return: ringbuf::Producer<Box<SaveBuffer>>
 	) -> anyhow::Result<Box<ArpApplication>> {
-		let serializable: Box<ArpApplicationSerializable> = Box::new(serde_json::from_reader(reader)?);
+		let mut serializable: Box<ArpApplicationSerializable> = Box::new(serde_json::from_reader(reader)?);
 		let n_arps = serializable.arp_instances.len();
 		if n_arps <= 0 {
 			anyhow::bail!("Illegal number of arpeggiators");
@@ -82,10 +99,29 @@ impl ArpApplication {
 		if serializable.routing_matrix.len() != n_arps || serializable.routing_matrix[0].len() != n_arps {
 			anyhow::bail!("Routing matrix size must match number of arpeggiators");
 		}
+		if let Some(in_channel) = serializable.in_channel.take() {
+			for instance in serializable.arp_instances.iter_mut() {
+				instance.in_channel = in_channel;
+			}
+		}
+		if let Some(out_channel) = serializable.out_channel.take() {
+			for instance in serializable.arp_instances.iter_mut() {
+				instance.out_channel = out_channel;
+			}
+		}
+		// different seeds, so that the arpeggiators don't make the same random decisions
+		for (i, instance) in serializable.arp_instances.iter_mut().enumerate() {
+			instance.arp.seed_random(i as u32 + 1);
+		}
 
 		Ok(Box::new(ArpApplication {
 			time: 0,
 			restart_transport_pending: false,
+			stop_transport_pending: false,
+			continue_transport_pending: false,
+			playing: true,
+			next_transport_clock: 0,
+			expected_transport_position: 0.0,
 			last_midiclock_received: 0,
 			next_midiclock_to_send: 0,
 			serializable: *serializable,
@@ -93,7 +129,9 @@ impl ArpApplication {
 			ui: LaunchpadX::new(),
 			gui_controller: GuiController::new(),
 			save_buffer_receive,
-			save_buffer_return
+			save_buffer_return,
+			osc_requests: None,
+			osc_replies: None
 		}))
 	}
 
@@ -103,13 +141,20 @@ impl ArpApplication {
 		save_buffer_return: ringbuf::Producer<Box<SaveBuffer>>
 	) -> Box<ArpApplication> {
 		let mut arp_instances = Vec::new();
-		for _ in 0..n_arps {
-			arp_instances.push(ArpeggiatorInstance::new());
+		for i in 0..n_arps {
+			let mut instance = ArpeggiatorInstance::new();
+			instance.arp.seed_random(i as u32 + 1);
+			arp_instances.push(instance);
 		}
 
 		Box::new(ArpApplication {
 			time: 0,
 			restart_transport_pending: false,
+			stop_transport_pending: false,
+			continue_transport_pending: false,
+			playing: true,
+			next_transport_clock: 0,
+			expected_transport_position: 0.0,
 			last_midiclock_received: 0,
 			next_midiclock_to_send: 0,
 			serializable: ArpApplicationSerializable {
@@ -118,26 +163,348 @@ impl ArpApplication {
 				arp_instances,
 				routing_matrix: vec![vec![false; n_arps]; n_arps],
 				active_arp: 0,
-				in_channel: Channel(0),
-				out_channel: Channel(0)
+				in_channel: None,
+				out_channel: None
 			},
 			old_routing_matrix: vec![vec![false; n_arps]; n_arps],
 			ui: LaunchpadX::new(),
 			gui_controller: GuiController::new(),
 			save_buffer_receive,
-			save_buffer_return
+			save_buffer_return,
+			osc_requests: None,
+			osc_replies: None
 		})
 	}
 
+	/// Makes the application process requests from an [`OscServer`](crate::osc::OscServer).
+	pub fn connect_osc(
+		&mut self,
+		requests: ringbuf::Consumer<OscRequest>,
+		replies: ringbuf::Producer<OscReply>
+	) {
+		self.osc_requests = Some(requests);
+		self.osc_replies = Some(replies);
+	}
+
 	pub fn n_arps(&self) -> usize { self.serializable.arp_instances.len() }
 
+	#[cfg(test)]
+	pub fn arp_instance_mut(&mut self, index: usize) -> &mut ArpeggiatorInstance {
+		&mut self.serializable.arp_instances[index]
+	}
+
+	fn process_osc_requests(&mut self) {
+		while let Some(request) = self.osc_requests.as_mut().and_then(|r| r.pop()) {
+			let reply = match request.value {
+				Some(value) => match self.osc_set(request.parameter, value) {
+					Some(()) => None,
+					None => Some(None)
+				},
+				None => Some(self.osc_get(request.parameter))
+			};
+			if let (Some(value), Some(replies)) = (reply, self.osc_replies.as_mut()) {
+				replies
+					.push(OscReply {
+						parameter: request.parameter,
+						value,
+						receiver: request.sender
+					})
+					.ok();
+			}
+		}
+	}
+
+	/// Returns `None` if the parameter does not exist or the value is invalid.
+	fn osc_set(&mut self, parameter: Parameter, value: Value) -> Option<()> {
+		let n_arps = self.n_arps();
+		let serializable = &mut self.serializable;
+		let int_in_range = |value: &Value, range: std::ops::Range<i32>| match value {
+			Value::Int(value) if range.contains(value) => Some(*value),
+			_ => None
+		};
+
+		match parameter {
+			Parameter::ActiveArp => {
+				serializable.active_arp = int_in_range(&value, 0..n_arps as i32)? as usize;
+			}
+			Parameter::ClockMode => match value {
+				Value::ClockMode(clock_mode) => serializable.clock_mode = clock_mode,
+				_ => return None
+			},
+			Parameter::Tempo => match value {
+				Value::Float(bpm) if bpm >= 20.0 && bpm <= 400.0 => {
+					serializable.time_between_midiclocks = (48000.0 * 60.0 / 24.0 / bpm) as u64;
+				}
+				_ => return None
+			},
+			Parameter::Transport => match value {
+				Value::Transport(Transport::Restart) => self.restart_transport_pending = true,
+				Value::Transport(Transport::Stop) => self.stop_transport_pending = true,
+				Value::Transport(Transport::Continue) => self.continue_transport_pending = true,
+				_ => return None
+			},
+			Parameter::Routing(from, to) => {
+				// like in the GUI, only routing to later arpeggiators is possible
+				if from >= to || to >= n_arps {
+					return None;
+				}
+				serializable.routing_matrix[from][to] = int_in_range(&value, 0..2)? != 0;
+			}
+			Parameter::ActivePattern(arp) => {
+				let instance = serializable.arp_instances.get_mut(arp)?;
+				let pattern = int_in_range(&value, 0..instance.patterns.len() as i32)? as usize;
+				instance.queue_pattern(pattern);
+			}
+			Parameter::LaunchQuantization(arp) => match value {
+				Value::LaunchQuantization(quantization) => {
+					serializable.arp_instances.get_mut(arp)?.launch_quantization = quantization;
+				}
+				_ => return None
+			},
+			Parameter::Chain(arp) => match value {
+				Value::ChainEntries(entries) => {
+					let instance = serializable.arp_instances.get_mut(arp)?;
+					if entries
+						.iter()
+						.any(|entry| entry.pattern >= instance.patterns.len())
+					{
+						return None;
+					}
+					instance.chain.entries = entries;
+				}
+				_ => return None
+			},
+			Parameter::ChainOneShot(arp) => {
+				serializable.arp_instances.get_mut(arp)?.chain.one_shot =
+					int_in_range(&value, 0..2)? != 0;
+			}
+			Parameter::TicksPerStep(arp) => {
+				serializable.arp_instances.get_mut(arp)?.ticks_per_step =
+					int_in_range(&value, 1..97)? as u32;
+			}
+			Parameter::ChordHold(arp) => {
+				let arp = &mut serializable.arp_instances.get_mut(arp)?.arp;
+				let mode = int_in_range(&value, 0..3)?;
+				arp.chord_hold = mode == 1;
+				arp.latch = mode == 2;
+				arp.chord_settle_time = if mode != 0 { 48000 / 40 } else { 0 };
+			}
+			Parameter::ChordOrder(arp) => match value {
+				Value::ChordOrder(order) => {
+					serializable.arp_instances.get_mut(arp)?.arp.chord_order = order;
+				}
+				_ => return None
+			},
+			Parameter::VelocitySource(arp) => match value {
+				Value::VelocitySource(source) => {
+					serializable.arp_instances.get_mut(arp)?.arp.velocity_source = source;
+				}
+				_ => return None
+			},
+			Parameter::InChannel(arp) => {
+				serializable.arp_instances.get_mut(arp)?.in_channel =
+					Channel(int_in_range(&value, 0..16)? as u8);
+			}
+			Parameter::OutChannel(arp) => {
+				serializable.arp_instances.get_mut(arp)?.out_channel =
+					Channel(int_in_range(&value, 0..16)? as u8);
+			}
+			Parameter::Omni(arp) => {
+				serializable.arp_instances.get_mut(arp)?.omni = int_in_range(&value, 0..2)? != 0;
+			}
+			Parameter::Fill(arp) => {
+				serializable.arp_instances.get_mut(arp)?.arp.fill =
+					int_in_range(&value, 0..2)? != 0;
+			}
+			Parameter::Mute(arp) => {
+				serializable.arp_instances.get_mut(arp)?.mixer.muted =
+					int_in_range(&value, 0..2)? != 0;
+			}
+			Parameter::Solo(arp) => {
+				serializable.arp_instances.get_mut(arp)?.mixer.solo =
+					int_in_range(&value, 0..2)? != 0;
+			}
+			Parameter::RouteWhenMuted(arp) => {
+				serializable
+					.arp_instances
+					.get_mut(arp)?
+					.mixer
+					.route_when_muted = int_in_range(&value, 0..2)? != 0;
+			}
+			Parameter::Slider(arp, slider) => {
+				let mut faders = serializable.arp_instances.get_mut(arp)?.faders_mut();
+				let (fader_value, range) = faders.get_mut(slider)?.as_mut()?;
+				match value {
+					Value::Float(value) => {
+						**fader_value = value.clamp(*range.start(), *range.end())
+					}
+					_ => return None
+				}
+			}
+			Parameter::SliderCc(arp, slider) => match value {
+				Value::CcBinding(binding) => {
+					let instance = serializable.arp_instances.get_mut(arp)?;
+					instance.faders().get(slider)?.as_ref()?;
+					if let Some(binding) = binding {
+						instance.learn_cc(slider, binding.controller);
+					}
+					instance.cc_bindings[slider] = binding;
+				}
+				_ => return None
+			},
+			Parameter::Scale(arp) => match value {
+				Value::Notes(mut notes) => {
+					let instance = serializable.arp_instances.get_mut(arp)?;
+					notes.sort_unstable();
+					if !notes.is_empty() {
+						// like in the GUI, scales only work with octave repetition
+						for pattern in instance.patterns.iter_mut() {
+							pattern.repeat_mode = RepeatMode::Repeat(12);
+						}
+					}
+					else {
+						instance.arp.scale_base_override = None;
+					}
+					instance.arp.scale = notes;
+				}
+				_ => return None
+			},
+			Parameter::ScaleBase(arp) => {
+				let arp = &mut serializable.arp_instances.get_mut(arp)?.arp;
+				arp.scale_base_override = match int_in_range(&value, -1..128)? {
+					-1 => None,
+					note => Some(Note(note as u8))
+				};
+			}
+			Parameter::PatternLength(arp, pattern) => {
+				let pattern = serializable
+					.arp_instances
+					.get_mut(arp)?
+					.patterns
+					.get_mut(pattern)?;
+				let capacity = pattern.pattern.capacity() as i32;
+				pattern
+					.pattern
+					.resize_default(int_in_range(&value, 1..capacity + 1)? as usize)
+					.ok();
+			}
+			Parameter::RepeatMode(arp, pattern) => match value {
+				Value::RepeatMode(repeat_mode) => {
+					let instance = serializable.arp_instances.get_mut(arp)?;
+					if !instance.arp.scale.is_empty() && repeat_mode != RepeatMode::Repeat(12) {
+						return None;
+					}
+					instance.patterns.get_mut(pattern)?.repeat_mode = repeat_mode;
+				}
+				_ => return None
+			},
+			Parameter::Step(arp, pattern, step) => match value {
+				Value::Entries(entries) => {
+					*serializable
+						.arp_instances
+						.get_mut(arp)?
+						.patterns
+						.get_mut(pattern)?
+						.pattern
+						.get_mut(step)? = entries;
+				}
+				_ => return None
+			}
+		}
+		Some(())
+	}
+
+	/// Returns `None` if the parameter does not exist.
+	fn osc_get(&self, parameter: Parameter) -> Option<Value> {
+		let serializable = &self.serializable;
+		let instance = |arp: usize| serializable.arp_instances.get(arp);
+
+		Some(match parameter {
+			Parameter::ActiveArp => Value::Int(serializable.active_arp as i32),
+			Parameter::ClockMode => Value::ClockMode(serializable.clock_mode),
+			Parameter::Tempo => {
+				Value::Float(48000.0 * 60.0 / 24.0 / serializable.time_between_midiclocks as f32)
+			}
+			Parameter::Routing(from, to) => {
+				Value::Int(*serializable.routing_matrix.get(from)?.get(to)? as i32)
+			}
+			Parameter::Transport => Value::Transport(if self.playing {
+				Transport::Continue
+			}
+			else {
+				Transport::Stop
+			}),
+			Parameter::ActivePattern(arp) => Value::Int(instance(arp)?.active_pattern as i32),
+			Parameter::LaunchQuantization(arp) => {
+				Value::LaunchQuantization(instance(arp)?.launch_quantization)
+			}
+			Parameter::Chain(arp) => Value::ChainEntries(instance(arp)?.chain.entries.clone()),
+			Parameter::ChainOneShot(arp) => Value::Int(instance(arp)?.chain.one_shot as i32),
+			Parameter::TicksPerStep(arp) => Value::Int(instance(arp)?.ticks_per_step as i32),
+			Parameter::ChordHold(arp) => {
+				let arp = &instance(arp)?.arp;
+				Value::Int(if arp.latch { 2 } else { arp.chord_hold as i32 })
+			}
+			Parameter::ChordOrder(arp) => Value::ChordOrder(instance(arp)?.arp.chord_order),
+			Parameter::VelocitySource(arp) => {
+				Value::VelocitySource(instance(arp)?.arp.velocity_source)
+			}
+			Parameter::InChannel(arp) => Value::Int(instance(arp)?.in_channel.0 as i32),
+			Parameter::OutChannel(arp) => Value::Int(instance(arp)?.out_channel.0 as i32),
+			Parameter::Omni(arp) => Value::Int(instance(arp)?.omni as i32),
+			Parameter::Fill(arp) => Value::Int(instance(arp)?.arp.fill as i32),
+			Parameter::Mute(arp) => Value::Int(instance(arp)?.mixer.muted as i32),
+			Parameter::Solo(arp) => Value::Int(instance(arp)?.mixer.solo as i32),
+			Parameter::RouteWhenMuted(arp) => {
+				Value::Int(instance(arp)?.mixer.route_when_muted as i32)
+			}
+			Parameter::Slider(arp, slider) => {
+				Value::Float(instance(arp)?.faders().get(slider)?.as_ref()?.0)
+			}
+			Parameter::SliderCc(arp, slider) => {
+				instance(arp)?.faders().get(slider)?.as_ref()?;
+				Value::CcBinding(instance(arp)?.cc_bindings[slider])
+			}
+			Parameter::Scale(arp) => Value::Notes(instance(arp)?.arp.scale.clone()),
+			Parameter::ScaleBase(arp) => Value::Int(
+				instance(arp)?
+					.arp
+					.scale_base_override
+					.map(|note| note.0 as i32)
+					.unwrap_or(-1)
+			),
+			Parameter::PatternLength(arp, pattern) => {
+				Value::Int(instance(arp)?.patterns.get(pattern)?.pattern.len() as i32)
+			}
+			Parameter::RepeatMode(arp, pattern) => {
+				Value::RepeatMode(instance(arp)?.patterns.get(pattern)?.repeat_mode.clone())
+			}
+			Parameter::Step(arp, pattern, step) => Value::Entries(
+				instance(arp)?
+					.patterns
+					.get(pattern)?
+					.pattern
+					.get(step)?
+					.clone()
+			)
+		})
+	}
+
 	fn process_ui_input(&mut self, use_external_clock: bool, frame: &mut impl DriverFrame) {
+		// the pattern select screen shows the queued patterns as selected
 		// FIXME magic (huge) constant
-		let mut active_patterns: heapless::Vec<usize, 64> = self
+		let selected_patterns: heapless::Vec<usize, 64> = self
 			.serializable
 			.arp_instances
 			.iter()
-			.map(|instance| instance.active_pattern)
+			.map(|instance| instance.queued_pattern().unwrap_or(instance.active_pattern))
+			.collect();
+		let mut active_patterns = selected_patterns.clone();
+		let mut mixer: heapless::Vec<MixerState, 64> = self
+			.serializable
+			.arp_instances
+			.iter()
+			.map(|instance| instance.mixer)
 			.collect();
 
 		let gui_controller = &mut self.gui_controller;
@@ -148,61 +515,168 @@ impl ArpApplication {
 		let active_arp = &mut self.serializable.active_arp;
 		let routing_matrix = &mut self.serializable.routing_matrix;
 		let restart_transport_pending = &mut self.restart_transport_pending;
+		let stop_transport_pending = &mut self.stop_transport_pending;
+		let continue_transport_pending = &mut self.continue_transport_pending;
 
 		for ev in frame.read_ui_events() {
 			println!("event!");
 			self.ui.handle_midi(&ev.event, |_ui, event| {
 				gui_controller.handle_input(
 					event,
-					&mut arp_instance.patterns[arp_instance.active_pattern],
-					8, // FIXME
-					&mut active_patterns,
-					active_arp,
-					restart_transport_pending,
-					use_external_clock,
-					clock_mode,
-					time_between_midiclocks,
-					&mut arp_instance.ticks_per_step,
-					&mut arp_instance.arp.chord_hold,
-					&mut arp_instance.arp.chord_settle_time,
-					&mut arp_instance.arp.scale,
-					&mut arp_instance.arp.scale_base_override,
-					&mut [
-						Some((&mut arp_instance.arp.global_length_modifier, 0.0..=2.0)),
-						None,
-						Some((
+					&mut InputContext {
+						pattern: &mut arp_instance.patterns[arp_instance.active_pattern],
+						n_patterns: 8, // FIXME
+						active_pattern: &mut active_patterns,
+						active_arp,
+						launch_quantization: &mut arp_instance.launch_quantization,
+						restart_transport_pending,
+						stop_transport_pending,
+						continue_transport_pending,
+						use_external_clock,
+						clock_mode,
+						time_between_midiclocks,
+						ticks_per_step: &mut arp_instance.ticks_per_step,
+						chord_hold: &mut arp_instance.arp.chord_hold,
+						latch: &mut arp_instance.arp.latch,
+						in_channel: &mut arp_instance.in_channel,
+						omni: &mut arp_instance.omni,
+						out_channel: &mut arp_instance.out_channel,
+						velocity_source: &mut arp_instance.arp.velocity_source,
+						chord_order: &mut arp_instance.arp.chord_order,
+						fill: &mut arp_instance.arp.fill,
+						chord_settle_time: &mut arp_instance.arp.chord_settle_time,
+						scale: &mut arp_instance.arp.scale,
+						scale_base_override: &mut arp_instance.arp.scale_base_override,
+						// like ArpeggiatorInstance::faders_mut(), which we can't call here
+						// because it would borrow the whole instance
+						fader_values: &mut faders(
+							&mut arp_instance.arp.global_length_modifier,
+							&mut arp_instance.swing,
 							&mut arp_instance.arp.intensity_length_modifier_amount,
-							0.0..=2.0
-						)),
-						None,
-						Some((&mut arp_instance.arp.global_velocity, 0.0..=2.0)),
-						None,
-						Some((&mut arp_instance.arp.intensity_velocity_amount, 0.0..=2.0))
-					],
-					routing_matrix,
-					time
+							&mut arp_instance.arp.humanize_timing,
+							&mut arp_instance.arp.global_velocity,
+							&mut arp_instance.arp.humanize_velocity,
+							&mut arp_instance.arp.intensity_velocity_amount,
+							&mut arp_instance.arp.input_velocity_amount
+						),
+						cc_bindings: &mut arp_instance.cc_bindings,
+						routing_matrix,
+						chain: &mut arp_instance.chain,
+						mixer: &mut mixer,
+						time
+					}
 				);
 			});
 		}
 
-		for (active_pattern, instance) in active_patterns
+		for ((active_pattern, selected_pattern), instance) in active_patterns
 			.iter()
+			.zip(selected_patterns.iter())
 			.zip(self.serializable.arp_instances.iter_mut())
 		{
-			instance.active_pattern = *active_pattern;
+			if active_pattern != selected_pattern {
+				instance.queue_pattern(*active_pattern);
+			}
+		}
+		for (state, instance) in mixer.iter().zip(self.serializable.arp_instances.iter_mut()) {
+			instance.mixer = *state;
+		}
+	}
+
+	/// Generates transport events and clocks from the JACK transport position
+	fn follow_transport(
+		&mut self,
+		transport: TransportPosition,
+		len: u32,
+		transport_events: &mut TransportEventVec
+	) {
+		let frames_per_clock = transport.frames_per_beat / 24.0;
+		let position = transport.beats * 24.0;
+
+		if !transport.rolling {
+			if self.playing {
+				transport_events.push((self.time, MidiEvent::Stop)).ok();
+				self.playing = false;
+			}
+			return;
+		}
+
+		let relocated = (position - self.expected_transport_position).abs() > 0.5;
+		if !self.playing || relocated {
+			if self.playing {
+				transport_events.push((self.time, MidiEvent::Stop)).ok();
+			}
+
+			// MIDI can only seek to sixteenth notes, so we skip all clocks until the next one
+			let song_position = ((position - 0.5) / 6.0).ceil().clamp(0.0, 0x3FFF as f64) as u64;
+			if song_position == 0 {
+				transport_events.push((self.time, MidiEvent::Start)).ok();
+			}
+			else {
+				transport_events
+					.push((self.time, MidiEvent::SongPosition(song_position as u16)))
+					.ok();
+				transport_events.push((self.time, MidiEvent::Continue)).ok();
+			}
+			self.playing = true;
+			self.next_transport_clock = song_position * 6;
+		}
+		self.expected_transport_position = position + len as f64 / frames_per_clock;
+
+		loop {
+			let offset = ((self.next_transport_clock as f64 - position) * frames_per_clock)
+				.round()
+				.max(0.0);
+			if offset >= len as f64 {
+				break;
+			}
+			transport_events
+				.push((self.time + offset as u64, MidiEvent::Clock))
+				.ok();
+			self.next_transport_clock += 1;
 		}
 	}
 
 	fn process_clocks(
 		&mut self,
 		use_external_clock: bool,
+		transport: Option<TransportPosition>,
 		frame: &mut impl DriverFrame
 	) -> TransportEventVec {
 		let mut transport_events = TransportEventVec::new();
 
+		if let Some(transport) = transport {
+			// the transport buttons have no effect when following the JACK transport
+			self.restart_transport_pending = false;
+			self.stop_transport_pending = false;
+			self.continue_transport_pending = false;
+
+			for event in frame.read_events(0) {
+				if let MidiEvent::Clock = event.event {
+					self.last_midiclock_received = self.time;
+				}
+			}
+
+			self.follow_transport(transport, frame.len(), &mut transport_events);
+			return transport_events;
+		}
+
 		if self.restart_transport_pending {
 			transport_events.push((self.time, MidiEvent::Start)).ok();
 			self.restart_transport_pending = false;
+			self.playing = true;
+		}
+		if self.stop_transport_pending {
+			transport_events.push((self.time, MidiEvent::Stop)).ok();
+			self.stop_transport_pending = false;
+			self.playing = false;
+		}
+		if self.continue_transport_pending {
+			if !self.playing {
+				transport_events.push((self.time, MidiEvent::Continue)).ok();
+			}
+			self.continue_transport_pending = false;
+			self.playing = true;
 		}
 
 		for event in frame.read_events(0) {
@@ -216,8 +690,16 @@ impl ArpApplication {
 						transport_events.push((timestamp, MidiEvent::Clock)).ok();
 					}
 				}
-				MidiEvent::Start => {
-					transport_events.push((timestamp, MidiEvent::Start)).ok();
+				MidiEvent::Start | MidiEvent::Continue => {
+					transport_events.push((timestamp, event.event)).ok();
+					self.playing = true;
+				}
+				MidiEvent::Stop => {
+					transport_events.push((timestamp, event.event)).ok();
+					self.playing = false;
+				}
+				MidiEvent::SongPosition(_) => {
+					transport_events.push((timestamp, event.event)).ok();
 				}
 				_ => ()
 			}
@@ -263,30 +745,50 @@ impl ArpApplication {
 			.iter()
 			.map(|instance| instance.active_pattern)
 			.collect();
+		let queued_patterns: heapless::Vec<Option<usize>, 64> = self
+			.serializable
+			.arp_instances
+			.iter()
+			.map(|instance| instance.queued_pattern())
+			.collect();
+		let mixer: heapless::Vec<MixerState, 64> = self
+			.serializable
+			.arp_instances
+			.iter()
+			.map(|instance| instance.mixer)
+			.collect();
 		let arp_instance = &mut self.serializable.arp_instances[self.serializable.active_arp];
 		self.gui_controller.draw(
-			&arp_instance.patterns[arp_instance.active_pattern],
-			&active_patterns,
-			self.serializable.active_arp,
-			arp_instance.currently_playing_tick(),
-			use_external_clock,
-			external_clock_present,
-			self.serializable.clock_mode,
-			arp_instance.ticks_per_step,
-			arp_instance.arp.chord_hold,
-			&arp_instance.arp.scale,
-			arp_instance.arp.scale_base_override,
-			&[
-				Some((arp_instance.arp.global_length_modifier, 0.0..=2.0)),
-				None,
-				Some((arp_instance.arp.intensity_length_modifier_amount, 0.0..=2.0)),
-				None,
-				Some((arp_instance.arp.global_velocity, 0.0..=2.0)),
-				None,
-				Some((arp_instance.arp.intensity_velocity_amount, 0.0..=2.0))
-			],
-			&self.serializable.routing_matrix,
-			self.time,
+			&DrawContext {
+				pattern: &arp_instance.patterns[arp_instance.active_pattern],
+				active_pattern: &active_patterns,
+				queued_pattern: &queued_patterns,
+				active_arp: self.serializable.active_arp,
+				launch_quantization: arp_instance.launch_quantization,
+				step: arp_instance.currently_playing_tick(),
+				total_step: arp_instance.currently_playing_total_tick(),
+				use_external_clock,
+				external_clock_present,
+				clock_mode: self.serializable.clock_mode,
+				ticks_per_step: arp_instance.ticks_per_step,
+				playing: self.playing,
+				chord_hold: arp_instance.arp.chord_hold,
+				latch: arp_instance.arp.latch,
+				in_channel: arp_instance.in_channel,
+				omni: arp_instance.omni,
+				out_channel: arp_instance.out_channel,
+				velocity_source: arp_instance.arp.velocity_source,
+				chord_order: arp_instance.arp.chord_order,
+				fill: arp_instance.arp.fill,
+				scale: &arp_instance.arp.scale,
+				scale_base_override: arp_instance.arp.scale_base_override,
+				fader_values: &arp_instance.faders(),
+				cc_bindings: &arp_instance.cc_bindings,
+				routing_matrix: &self.serializable.routing_matrix,
+				chain: &arp_instance.chain,
+				mixer: &mixer,
+				time: self.time
+			},
 			|pos, color| {
 				ui.set(pos, color, |bytes| {
 					frame.send_ui_event(frame.len() - 1, bytes).ok();
@@ -308,14 +810,23 @@ impl ArpApplication {
 			});
 		}
 
-		let external_clock_present = self.time - self.last_midiclock_received <= 48000;
+		self.process_osc_requests();
+
+		// without a timebase master, JackTransport mode behaves like Auto
+		let transport = match self.serializable.clock_mode {
+			ClockMode::JackTransport => frame.transport(),
+			_ => None
+		};
+		let external_clock_present =
+			self.time - self.last_midiclock_received <= 48000 || transport.is_some();
 		let use_external_clock = match self.serializable.clock_mode {
 			ClockMode::Internal => false,
 			ClockMode::External => true,
-			ClockMode::Auto => external_clock_present
+			ClockMode::Auto | ClockMode::JackTransport => external_clock_present
 		};
 
-		let transport_events = self.process_clocks(use_external_clock, frame);
+		let was_playing = self.playing;
+		let transport_events = self.process_clocks(use_external_clock, transport, frame);
 
 		self.process_ui_input(use_external_clock, frame);
 		self.process_ui_output(
@@ -325,7 +836,17 @@ impl ArpApplication {
 			frame
 		);
 
+		// sent to every instance's output, in the order the pending events are sorted in
+		let mut sorted_transport_events = transport_events.clone();
+		sorted_transport_events.sort_unstable();
+
 		let n_instances = self.serializable.arp_instances.len();
+		let learning_fader = self.gui_controller.learning_fader();
+		let any_solo = self
+			.serializable
+			.arp_instances
+			.iter()
+			.any(|instance| instance.mixer.solo);
 		// TODO FIXME clean this up
 		for i in 0..n_instances {
 			let (instance, instance_tail) = self.serializable.arp_instances[i..]
@@ -337,31 +858,66 @@ impl ArpApplication {
 				let timestamp = self.time + event.time as u64;
 
 				match event.event {
-					MidiEvent::NoteOn(note, _velocity, channel) => {
-						if channel == self.serializable.in_channel {
-							instance.arp.note_on(note, timestamp)
+					MidiEvent::NoteOn(note, velocity, channel) => {
+						if instance.listens_to(channel) {
+							instance.arp.note_on(note, velocity, timestamp)
 						}
 					}
-					MidiEvent::NoteOff(note, channel) => {
-						if channel == self.serializable.in_channel {
+					MidiEvent::NoteOff(note, _, channel) => {
+						if instance.listens_to(channel) {
 							instance.arp.note_off(note, timestamp)
 						}
 					}
+					MidiEvent::ControlChange(controller, value, channel)
+						if instance.listens_to(channel) =>
+					{
+						if i == self.serializable.active_arp {
+							if let Some(fader) = learning_fader {
+								instance.learn_cc(fader, controller);
+							}
+						}
+						if !instance.control_change(controller, value) {
+							instance.add_pending_event(timestamp, event.event).ok();
+						}
+					}
+					MidiEvent::ControlChange(..)
+					| MidiEvent::ProgramChange(..)
+					| MidiEvent::ChannelAftertouch(..)
+					| MidiEvent::PitchBend(..) => {
+						// pass through to the synthesizer, like a keyboard's modulation wheel
+						if event.event.channel().map(|c| instance.listens_to(c)) == Some(true) {
+							instance.add_pending_event(timestamp, event.event).ok();
+						}
+					}
 					_ => ()
 				}
 			}
 
 			// tick
+			let mut playing = was_playing;
 			for (timestamp, event) in transport_events.iter() {
-				instance
-					.add_pending_event(*timestamp, *event)
-					.expect("Failed to write tick event");
+				if let MidiEvent::Stop = event {
+					instance.stop_transport(*timestamp);
+				}
 				match event {
 					MidiEvent::Clock => {
-						instance.tick_clock(*timestamp);
+						if playing {
+							instance.tick_clock(*timestamp);
+						}
 					}
 					MidiEvent::Start => {
 						instance.restart_transport();
+						playing = true;
+					}
+					MidiEvent::Continue => {
+						instance.continue_transport();
+						playing = true;
+					}
+					MidiEvent::Stop => {
+						playing = false;
+					}
+					MidiEvent::SongPosition(position) => {
+						instance.seek(*position as u32 * 6);
 					}
 					_ => ()
 				}
@@ -369,7 +925,23 @@ impl ArpApplication {
 
 			// output
 			let time = self.time;
-			let out_channel = self.serializable.out_channel;
+			let out_channel = instance.out_channel;
+			let audible = instance.mixer.audible(any_solo);
+			let route_note_ons = audible || instance.mixer.route_when_muted;
+
+			// end the sounding notes when muted. Their pending note offs are sent later
+			// nevertheless, which does no harm.
+			if instance.just_silenced(any_solo) {
+				for note in instance.pending_note_offs() {
+					frame
+						.send_event(
+							i,
+							0,
+							MidiEvent::NoteOff(note, DEFAULT_RELEASE_VELOCITY, out_channel)
+						)
+						.expect("Writing to MIDI buffer failed");
+				}
+			}
 			let routing_matrix = &self.serializable.routing_matrix;
 			let old_routing_matrix = &mut self.old_routing_matrix;
 			assert!(check_routing_matrix(routing_matrix));
@@ -379,29 +951,41 @@ impl ArpApplication {
 				if old_routing_matrix[i][j] && !routing_matrix[i][j] {
 					for note in instance.pending_note_offs() {
 						let other_context = &mut instance_tail[j - (i + 1)];
-						other_context.arp.note_off(note, self.time);
+						other_context.arp.routed_note_off(note, self.time);
 					}
 				}
 				old_routing_matrix[i][j] = routing_matrix[i][j];
 			}
 
+			let mut transport_events = sorted_transport_events.iter().peekable();
 			instance.process_pending_events(self.time + (frame.len() as u64), |events| {
 				for event in events {
+					while let Some(transport_event) =
+						transport_events.next_if(|transport_event| *transport_event < event)
+					{
+						frame
+							.send_event(i, (transport_event.0 - time) as u32, transport_event.1)
+							.expect("Writing to MIDI buffer failed");
+					}
+
 					for j in (i + 1)..n_instances {
 						let other_context = &mut instance_tail[j - (i + 1)];
 						if routing_matrix[i][j] {
 							match event.1 {
-								MidiEvent::NoteOn(note, _, _) => {
-									other_context.arp.note_on(note, event.0);
+								MidiEvent::NoteOn(note, velocity, _) if route_note_ons => {
+									other_context.arp.routed_note_on(note, velocity, event.0);
 								}
-								MidiEvent::NoteOff(note, _) => {
-									other_context.arp.note_off(note, event.0);
+								MidiEvent::NoteOff(note, ..) => {
+									other_context.arp.routed_note_off(note, event.0);
 								}
 								_ => ()
 							}
 						}
 					}
 
+					if let (MidiEvent::NoteOn(..), false) = (event.1, audible) {
+						continue;
+					}
 					frame
 						.send_event(
 							i,
@@ -411,6 +995,11 @@ impl ArpApplication {
 						.expect("Writing to MIDI buffer failed");
 				}
 			});
+			for (timestamp, event) in transport_events {
+				frame
+					.send_event(i, (timestamp - time) as u32, *event)
+					.expect("Writing to MIDI buffer failed");
+			}
 		}
 
 		self.time += frame.len() as u64;
@@ -424,3 +1013,217 @@ impl ArpApplication {
 		}
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn application(n_arps: usize) -> Box<ArpApplication> {
+		let (_, save_buffer_receive) = ringbuf::RingBuffer::new(1).split();
+		let (save_buffer_return, _) = ringbuf::RingBuffer::new(1).split();
+		ArpApplication::new(n_arps, save_buffer_receive, save_buffer_return)
+	}
+
+	/// Follows the transport for one frame, which is exactly one MIDI clock long.
+	/// Returns the transport events relative to the start of the frame.
+	fn follow(app: &mut ArpApplication, rolling: bool, clocks: f64) -> Vec<(u64, MidiEvent)> {
+		let transport = TransportPosition {
+			rolling,
+			beats: clocks / 24.0,
+			frames_per_beat: 24000.0
+		};
+		let mut events = TransportEventVec::new();
+		app.follow_transport(transport, 1000, &mut events);
+		let time = app.time;
+		app.time += 1000;
+		events.iter().map(|(t, event)| (t - time, *event)).collect()
+	}
+
+	#[test]
+	pub fn transport_start_stop() {
+		use MidiEvent::*;
+		let mut app = application(1);
+
+		assert_eq!(follow(&mut app, false, 0.0), [(0, Stop)]);
+		assert_eq!(follow(&mut app, false, 0.0), []);
+		assert_eq!(follow(&mut app, true, 0.0), [(0, Start), (0, Clock)]);
+		assert_eq!(follow(&mut app, true, 1.0), [(0, Clock)]);
+		// the clocks are placed at their exact position within the frame
+		assert_eq!(follow(&mut app, true, 1.75), [(250, Clock)]);
+		assert_eq!(follow(&mut app, false, 2.75), [(0, Stop)]);
+
+		// starting in the middle of the song continues from the next sixteenth note
+		assert_eq!(
+			follow(&mut app, true, 7.0),
+			[(0, SongPosition(2)), (0, Continue)]
+		);
+		for clocks in 8..12 {
+			assert_eq!(follow(&mut app, true, clocks as f64), []);
+		}
+		assert_eq!(follow(&mut app, true, 12.0), [(0, Clock)]);
+	}
+
+	#[test]
+	pub fn transport_relocation() {
+		use MidiEvent::*;
+		let mut app = application(1);
+
+		assert_eq!(follow(&mut app, true, 0.0), [(0, Clock)]);
+		// jitter of less than half a clock is no relocation
+		assert_eq!(follow(&mut app, true, 1.4), [(0, Clock), (600, Clock)]);
+		assert_eq!(follow(&mut app, true, 2.4), [(600, Clock)]);
+		assert_eq!(
+			follow(&mut app, true, 96.0),
+			[(0, Stop), (0, SongPosition(16)), (0, Continue), (0, Clock)]
+		);
+		assert_eq!(follow(&mut app, true, 97.0), [(0, Clock)]);
+		assert_eq!(
+			follow(&mut app, true, 0.0),
+			[(0, Stop), (0, Start), (0, Clock)]
+		);
+	}
+
+	#[test]
+	pub fn ratchets_fit_into_pending_events() {
+		use crate::arpeggiator::{Entry, MAX_RATCHETS};
+		use crate::driver::buffered::FrameBuffers;
+		use crate::driver::TimestampedMidiEvent;
+
+		let mut app = application(1);
+		app.serializable.clock_mode = ClockMode::Internal;
+		for note in 0..16 {
+			app.serializable.arp_instances[0].patterns[0]
+				.set(
+					0,
+					Entry {
+						note,
+						len_steps: 4,
+						ratchets: MAX_RATCHETS,
+						..Entry::default()
+					}
+				)
+				.unwrap();
+		}
+
+		let mut buffers = FrameBuffers::new(1);
+		let (mut note_ons, mut note_offs, mut clocks) = (0, 0, 0);
+		let mut pitches = std::collections::BTreeSet::new();
+		for period in 0..1000 {
+			buffers.clear();
+			if period == 0 {
+				for note in 0..8 {
+					buffers.arp_inputs[0]
+						.push(TimestampedMidiEvent {
+							time: 0,
+							event: MidiEvent::NoteOn(Note(60 + note), 100, Channel(0))
+						})
+						.ok();
+				}
+			}
+			if period == 900 {
+				app.stop_transport_pending = true;
+			}
+			app.process(&mut buffers.frame(256, false));
+
+			for event in buffers.arp_outputs[0].iter() {
+				match event.event {
+					MidiEvent::NoteOn(note, ..) => {
+						note_ons += 1;
+						pitches.insert(note);
+					}
+					MidiEvent::NoteOff(..) => note_offs += 1,
+					MidiEvent::Clock => clocks += 1,
+					_ => ()
+				}
+			}
+		}
+		// all clocks are passed on, also while stopped, and every note is ended
+		assert_eq!(clocks, 1000 * 256 / 1000);
+		assert_eq!(note_ons, note_offs);
+		// with fewer ratchets, all 8 chord notes are played in both octaves
+		assert_eq!(pitches.len(), 16);
+	}
+
+	/// Runs the application for the given number of periods and returns the events
+	/// sent to every arpeggiator output
+	fn run(
+		app: &mut ArpApplication,
+		buffers: &mut crate::driver::buffered::FrameBuffers,
+		periods: usize
+	) -> Vec<Vec<MidiEvent>> {
+		let mut outputs = vec![Vec::new(); buffers.arp_outputs.len()];
+		for _ in 0..periods {
+			app.process(&mut buffers.frame(256, false));
+			for (output, events) in outputs.iter_mut().zip(buffers.arp_outputs.iter()) {
+				output.extend(events.iter().map(|event| event.event));
+			}
+			buffers.clear();
+		}
+		outputs
+	}
+
+	fn note_ons(events: &[MidiEvent]) -> usize {
+		events
+			.iter()
+			.filter(|event| matches!(event, MidiEvent::NoteOn(..)))
+			.count()
+	}
+
+	#[test]
+	pub fn mute_and_routing() {
+		use crate::arpeggiator::Entry;
+		use crate::driver::buffered::FrameBuffers;
+		use crate::driver::TimestampedMidiEvent;
+
+		let mut app = application(2);
+		app.serializable.clock_mode = ClockMode::Internal;
+		app.serializable.routing_matrix[0][1] = true;
+		for instance in app.serializable.arp_instances.iter_mut() {
+			for step in 0..8 {
+				instance.patterns[0].set(step, Entry::default()).unwrap();
+			}
+		}
+
+		let mut buffers = FrameBuffers::new(2);
+		buffers.arp_inputs[0]
+			.push(TimestampedMidiEvent {
+				time: 0,
+				event: MidiEvent::NoteOn(Note(60), 100, Channel(0))
+			})
+			.ok();
+		let outputs = run(&mut app, &mut buffers, 100);
+		assert!(note_ons(&outputs[0]) > 0);
+		assert!(note_ons(&outputs[1]) > 0);
+
+		// mute while a note is sounding
+		let sounding = (0..100)
+			.find_map(|_| {
+				run(&mut app, &mut buffers, 1)[0]
+					.iter()
+					.find_map(|event| match event {
+						MidiEvent::NoteOn(note, ..) => Some(*note),
+						_ => None
+					})
+			})
+			.unwrap();
+		app.serializable.arp_instances[0].mixer.muted = true;
+		let outputs = run(&mut app, &mut buffers, 1);
+		assert!(matches!(
+			outputs[0].first(),
+			Some(MidiEvent::NoteOff(note, ..)) if *note == sounding
+		));
+
+		// the routed instance stops playing once the routed notes have been released
+		let outputs = run(&mut app, &mut buffers, 200);
+		assert_eq!(note_ons(&outputs[0]), 0);
+		let outputs = run(&mut app, &mut buffers, 100);
+		assert_eq!(note_ons(&outputs[0]), 0);
+		assert_eq!(note_ons(&outputs[1]), 0);
+
+		// unless the notes are still routed while muted
+		app.serializable.arp_instances[0].mixer.route_when_muted = true;
+		let outputs = run(&mut app, &mut buffers, 100);
+		assert_eq!(note_ons(&outputs[0]), 0);
+		assert!(note_ons(&outputs[1]) > 0);
+	}
+}
@@ -1,73 +1,203 @@
 // this file is part of arpfisch. For copyright and licensing details, see main.rs
 
-use crate::midi::{Channel, MidiEvent, Note};
+use crate::midi::{Channel, MidiEvent, Note, DEFAULT_RELEASE_VELOCITY};
+use crate::midi_learn::{CcBinding, CcMode, Takeover};
+use crate::random::Random;
 use crate::tempo_detector::TempoDetector;
 use heapless;
 use serde::{Deserialize, Serialize};
+use std::ops::RangeInclusive;
 
 #[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum RepeatMode {
 	Clamp,
 	Repeat(i32),
-	Mirror
+	Mirror,
+	/// Pattern notes beyond the chord are not played
+	Skip,
+	/// Each pattern note picks a chord note from a random order that changes with every loop
+	Random
 }
 
 impl RepeatMode {
-	pub fn get(&self, pitches: &[Note], index: isize) -> Option<Note> {
+	/// Returns which of the `len` chord notes is played for the pattern note `index`,
+	/// along with the number of semitones it is transposed by. `loop_count` is the number of
+	/// completed pattern loops.
+	pub fn index(&self, len: usize, index: isize, loop_count: usize) -> Option<(usize, i32)> {
 		use RepeatMode::*;
-		if pitches.is_empty() {
+		if len == 0 {
 			return None;
 		}
 		match *self {
 			Clamp => {
 				if index >= 0 {
-					if index < pitches.len() as isize {
-						Some(pitches[index as usize])
+					if index < len as isize {
+						Some((index as usize, 0))
 					}
 					else {
-						Some(*pitches.last().unwrap())
+						Some((len - 1, 0))
 					}
 				}
 				else {
-					let reverse_index = pitches.len() as isize + index;
+					let reverse_index = len as isize + index;
 					if reverse_index >= 0 {
-						Some(pitches[reverse_index as usize])
+						Some((reverse_index as usize, 0))
 					}
 					else {
-						Some(*pitches.first().unwrap())
+						Some((0, 0))
 					}
 				}
 			}
 
 			Repeat(transpose) => {
-				let repetition = div_floor(index, pitches.len());
-				pitches[modulo(index, pitches.len())].transpose(repetition as i32 * transpose)
+				let repetition = div_floor(index, len);
+				Some((modulo(index, len), repetition as i32 * transpose))
 			}
 
 			Mirror => {
-				if pitches.len() == 1 {
-					Some(pitches[0])
+				if len == 1 {
+					Some((0, 0))
 				}
 				else {
-					let repeated_index = modulo(index, 2 * pitches.len() - 2);
-					if repeated_index < pitches.len() {
-						Some(pitches[repeated_index])
+					let repeated_index = modulo(index, 2 * len - 2);
+					if repeated_index < len {
+						Some((repeated_index, 0))
 					}
 					else {
-						Some(pitches[2 * pitches.len() - 1 - repeated_index - 1])
+						Some((2 * len - 1 - repeated_index - 1, 0))
 					}
 				}
 			}
+
+			Skip => {
+				if (0..len as isize).contains(&index) {
+					Some((index as usize, 0))
+				}
+				else {
+					None
+				}
+			}
+
+			Random => {
+				let mut order: heapless::Vec<usize, 16> = (0..len).take(16).collect();
+				let mut random = crate::random::Random::new(loop_count as u32);
+				for i in (1..order.len()).rev() {
+					order.swap(i, random.next_u32() as usize % (i + 1));
+				}
+				Some((order[modulo(index, order.len())], 0))
+			}
 		}
 	}
 }
 
+/// Determines where the velocity of the generated notes comes from
+#[derive(Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum VelocitySource {
+	/// The step intensity, modified by the velocity sliders
+	Step,
+	/// The velocity of the corresponding input chord note
+	Input,
+	/// The step velocity, scaled by the input velocity according to `input_velocity_amount`
+	Blend
+}
+
+impl Default for VelocitySource {
+	fn default() -> VelocitySource { VelocitySource::Step }
+}
+
+/// Determines which chord note the pattern's row 0, row 1 etc refer to
+#[derive(Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ChordOrder {
+	/// Row 0 is the lowest note
+	Ascending,
+	/// Row 0 is the highest note
+	Descending,
+	/// Row 0 is the note that was pressed first
+	Arrival
+}
+
+impl ChordOrder {
+	fn sort(&self, chord: &mut [(Note, u8)]) {
+		match self {
+			ChordOrder::Ascending => chord.sort_unstable_by_key(|n| n.0),
+			ChordOrder::Descending => chord.sort_unstable_by(|a, b| b.0.cmp(&a.0)),
+			ChordOrder::Arrival => {}
+		}
+	}
+}
+
+impl Default for ChordOrder {
+	fn default() -> ChordOrder { ChordOrder::Ascending }
+}
+
+/// Decides on which passes through the pattern an entry is played
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum Condition {
+	Always,
+	/// `Ratio(a, b)` plays on the `a`-th of every `b` passes
+	Ratio(u8, u8),
+	First,
+	NotFirst,
+	/// Only while fill mode is enabled
+	Fill,
+	NotFill,
+	/// Only if the last evaluated condition in this arpeggiator was true
+	Previous,
+	NotPrevious
+}
+
+impl Default for Condition {
+	fn default() -> Condition { Condition::Always }
+}
+
+/// Velocity assumed for chords that were saved without velocities
+const DEFAULT_INPUT_VELOCITY: u8 = 100;
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Entry {
 	pub note: isize,
 	pub len_steps: u32,
 	pub intensity: f32,
-	pub transpose: i32
+	pub transpose: i32,
+	/// Chance that the entry is played on each pass, between 0 and 1
+	#[serde(default = "default_probability")]
+	pub probability: f32,
+	/// How often the note is repeated within its length
+	#[serde(default = "default_ratchets")]
+	pub ratchets: u32,
+	/// Velocity change across the repeats, between -1 (fading out) and 1 (fading in)
+	#[serde(default)]
+	pub ratchet_ramp: f32,
+	/// Micro-timing: how late the note is played, as a fraction of a step
+	#[serde(default)]
+	pub delay: f32,
+	#[serde(default)]
+	pub condition: Condition,
+	/// Transposition in degrees of the arpeggiator's scale. Ignored if no scale is set.
+	#[serde(default)]
+	pub degrees: i32
+}
+
+fn default_probability() -> f32 { 1.0 }
+fn default_ratchets() -> u32 { 1 }
+
+pub const MAX_RATCHETS: u32 = 8;
+
+impl Default for Entry {
+	fn default() -> Entry {
+		Entry {
+			note: 0,
+			len_steps: 1,
+			intensity: 1.0,
+			transpose: 0,
+			probability: default_probability(),
+			ratchets: default_ratchets(),
+			ratchet_ramp: 0.0,
+			delay: 0.0,
+			condition: Condition::Always,
+			degrees: 0
+		}
+	}
 }
 
 impl Entry {
@@ -81,20 +211,80 @@ impl Entry {
 			self.len_steps as f32 - 1.0 + (modifier / 2.0).clamp(0.0, 0.9999)
 		}
 	}
+
+	/// Velocity factor for the `i`-th repeat. The loudest repeat is at full velocity,
+	/// the softest one at `1 / ratchets` with a ramp of 1 or -1.
+	pub fn ratchet_velocity(&self, i: u32) -> f32 {
+		let ratchets = self.ratchets.max(1) as f32;
+		if self.ratchet_ramp >= 0.0 {
+			1.0 - self.ratchet_ramp * (1.0 - (i + 1) as f32 / ratchets)
+		}
+		else {
+			1.0 + self.ratchet_ramp * i as f32 / ratchets
+		}
+	}
 }
 
 #[derive(Clone, Serialize, Deserialize)]
 pub struct ArpeggioData {
 	pub repeat_mode: RepeatMode,
-	pub pattern: heapless::Vec<heapless::Vec<Entry, 16>, 64>
+	pub pattern: heapless::Vec<heapless::Vec<Entry, 16>, 64>,
+	/// Rows (by `Entry::note`) that loop independently with their own length instead
+	/// of the pattern length
+	#[serde(default)]
+	pub row_lengths: heapless::Vec<(isize, usize), 16>
 }
 
 impl ArpeggioData {
+	/// The length the row loops with, which is never longer than the pattern
+	pub fn row_len(&self, note: isize) -> usize {
+		self.own_row_len(note)
+			.unwrap_or(usize::MAX)
+			.min(self.pattern.len())
+	}
+	/// Never 0, even if a save file says so
+	pub fn own_row_len(&self, note: isize) -> Option<usize> {
+		self.row_lengths
+			.iter()
+			.find(|(n, _)| *n == note)
+			.map(|(_, len)| (*len).max(1))
+	}
+	/// Gives the row its own length, or makes it follow the pattern length again
+	pub fn set_row_len(&mut self, note: isize, len: Option<usize>) {
+		self.row_lengths.retain(|(n, _)| *n != note);
+		if let Some(len) = len {
+			self.row_lengths.push((note, len.max(1))).ok();
+		}
+	}
+	/// Returns the entries to play at pattern position `step`. Rows with their own
+	/// length play the position `total_steps` (counted since the start) modulo that length.
+	pub fn entries_at(&self, step: usize, total_steps: usize) -> impl Iterator<Item = &Entry> {
+		let pattern_rows = self.pattern[step]
+			.iter()
+			.filter(move |e| self.own_row_len(e.note).is_none());
+		let own_rows = self.row_lengths.iter().flat_map(move |(note, _)| {
+			self.pattern[total_steps % self.row_len(*note)]
+				.iter()
+				.filter(move |e| e.note == *note)
+		});
+		pattern_rows.chain(own_rows)
+	}
+
+	/// Yields nothing if `pos` lies beyond the end of the pattern, e.g. when a screen still
+	/// refers to a step of a longer pattern that is no longer active.
 	pub fn filter_mut(&mut self, pos: usize, note: isize) -> impl Iterator<Item = &mut Entry> {
-		self.pattern[pos].iter_mut().filter(move |e| e.note == note)
+		self.pattern
+			.get_mut(pos)
+			.into_iter()
+			.flatten()
+			.filter(move |e| e.note == note)
 	}
 	pub fn filter(&self, pos: usize, note: isize) -> impl Iterator<Item = &Entry> {
-		self.pattern[pos].iter().filter(move |e| e.note == note)
+		self.pattern
+			.get(pos)
+			.into_iter()
+			.flatten()
+			.filter(move |e| e.note == note)
 	}
 	/// Returns an error if the step can hold no more events
 	pub fn set(&mut self, pos: usize, entry: Entry) -> Result<(), Entry> {
@@ -130,20 +320,57 @@ pub struct Arpeggiator {
 	pub global_velocity: f32,
 	pub intensity_length_modifier_amount: f32,
 	pub intensity_velocity_amount: f32,
+	#[serde(default)]
+	pub velocity_source: VelocitySource,
+	#[serde(default)]
+	pub input_velocity_amount: f32,
+	/// Maximum random deviation of the note velocities
+	#[serde(default)]
+	pub humanize_velocity: f32,
+	/// Maximum random delay of the notes, as a fraction of a step. Notes are only ever moved
+	/// later, so a humanized note never starts before its step.
+	#[serde(default)]
+	pub humanize_timing: f32,
+	#[serde(default)]
+	pub chord_order: ChordOrder,
 	pub chord_settle_time: u64,
 	pub chord_hold: bool,
+	/// Additive chord hold: pressing a key adds it to the chord, pressing it again removes it
+	#[serde(default)]
+	pub latch: bool,
 	pub scale: heapless::Vec<Note, 16>,
 	pub scale_base_override: Option<Note>,
+	/// Enables the entries with the `Fill` condition
+	#[serde(skip)]
+	pub fill: bool,
 	stable_chord: heapless::Vec<Note, 16>,
+	/// Input velocities of the `stable_chord` notes
+	#[serde(default)]
+	stable_velocities: heapless::Vec<u8, 16>,
 
 	#[serde(skip)]
 	chord_hold_old: bool, // FIXME this should really not be there... use a setter instead
 	#[serde(skip)]
-	chord: heapless::Vec<Note, 16>,
+	latch_old: bool,
+	/// The pressed notes in the order of arrival
+	#[serde(skip)]
+	chord: heapless::Vec<(Note, u8), 16>,
+	#[serde(skip)]
+	last_velocity: u8,
 	#[serde(skip)]
 	chord_next_update_time: Option<u64>,
 	#[serde(skip)]
 	step: usize,
+	/// Number of completed passes through the pattern
+	#[serde(skip)]
+	loop_count: usize,
+	/// Number of steps played since the start
+	#[serde(skip)]
+	total_steps: usize,
+	#[serde(skip)]
+	previous_condition: bool,
+	#[serde(skip)]
+	random: Random,
 	#[serde(skip)]
 	scale_base_override_old: Option<Note> // meeeeh FIXME
 }
@@ -152,7 +379,9 @@ pub struct Arpeggiator {
 pub enum ClockMode {
 	Internal,
 	External,
-	Auto
+	Auto,
+	/// Follow the JACK transport's bar/beat/tick position
+	JackTransport
 }
 
 fn scale_from<const LEN: usize>(scale: &[Note], bottom: Note) -> heapless::Vec<Note, LEN> {
@@ -181,39 +410,82 @@ impl Arpeggiator {
 	pub fn new() -> Arpeggiator {
 		Arpeggiator {
 			step: 0,
+			loop_count: 0,
+			total_steps: 0,
+			previous_condition: false,
+			fill: false,
 			global_length_modifier: 1.0,
 			global_velocity: 1.0,
 			intensity_velocity_amount: 1.0,
 			intensity_length_modifier_amount: 0.0,
+			velocity_source: VelocitySource::Step,
+			input_velocity_amount: 0.0,
+			humanize_velocity: 0.0,
+			humanize_timing: 0.0,
+			chord_order: ChordOrder::Ascending,
 			chord: heapless::Vec::new(),
+			last_velocity: DEFAULT_INPUT_VELOCITY,
 			stable_chord: heapless::Vec::new(),
+			stable_velocities: heapless::Vec::new(),
 			chord_next_update_time: None,
 			chord_settle_time: 0,
 			chord_hold: false,
 			chord_hold_old: false,
+			latch: false,
+			latch_old: false,
 			scale: heapless::Vec::new(),
 			scale_base_override: None,
-			scale_base_override_old: None
+			scale_base_override_old: None,
+			random: Random::default()
+		}
+	}
+
+	/// Seeds the random number generator used for the step probabilities.
+	pub fn seed_random(&mut self, seed: u32) { self.random = Random::new(seed); }
+
+	fn set_stable_chord_from_scale(&mut self, bottom: Note) {
+		self.stable_chord = scale_from(&self.scale, bottom);
+		self.stable_velocities.clear();
+		for _ in 0..self.stable_chord.len() {
+			self.stable_velocities.push(self.last_velocity).ok();
 		}
 	}
 
-	pub fn note_on(&mut self, note: Note, time: u64) {
+	pub fn note_on(&mut self, note: Note, velocity: u8, time: u64) {
+		self.press(note, velocity, time, self.latch)
+	}
+	pub fn note_off(&mut self, note: Note, time: u64) { self.release(note, time, self.latch) }
+	/// Like `note_on`, but for notes routed from another instance, which are never latched
+	pub fn routed_note_on(&mut self, note: Note, velocity: u8, time: u64) {
+		self.press(note, velocity, time, false)
+	}
+	pub fn routed_note_off(&mut self, note: Note, time: u64) { self.release(note, time, false) }
+
+	fn press(&mut self, note: Note, velocity: u8, time: u64, latch: bool) {
+		self.last_velocity = velocity;
 		if self.scale.is_empty() {
-			if self.chord.iter().position(|n| *n == note).is_none() {
-				self.chord.push(note).ok();
-				self.chord.sort();
+			if let Some(i) = self.chord.iter().position(|n| n.0 == note) {
+				if latch {
+					self.chord.remove(i);
+					self.chord_next_update_time = Some(time + self.chord_settle_time);
+				}
+			}
+			else {
+				self.chord.push((note, velocity)).ok();
 				self.chord_next_update_time = Some(time + self.chord_settle_time);
 			}
 		}
 		else if self.scale_base_override.is_none() {
-			self.stable_chord = scale_from(&self.scale, note);
+			self.set_stable_chord_from_scale(note);
 		}
 	}
-	pub fn note_off(&mut self, note: Note, time: u64) {
-		if self.scale.is_empty() {
-			if let Some(i) = self.chord.iter().position(|n| *n == note) {
-				self.chord.swap_remove(i);
-				self.chord.sort();
+	fn release(&mut self, note: Note, time: u64, latch: bool) {
+		if self.scale.is_empty() && latch {
+			// latched notes are only removed by pressing them again
+		}
+		else if self.scale.is_empty() {
+			if let Some(i) = self.chord.iter().position(|n| n.0 == note) {
+				self.chord.remove(i);
 				if self.chord_hold && self.chord.is_empty() {
 					self.chord_next_update_time = None;
 				}
@@ -223,7 +495,7 @@ impl Arpeggiator {
 			}
 		}
 		else if self.scale_base_override.is_none() {
-			if !self.chord_hold {
+			if !self.chord_hold && !self.latch {
 				if let Some(bottom_note) = self.stable_chord.first() {
 					if *bottom_note == note {
 						self.stable_chord.clear();
@@ -232,10 +504,13 @@ impl Arpeggiator {
 			}
 		}
 	}
+	/// Plays the next step. Generates at most `max_notes` note on/off pairs, with fewer
+	/// ratchets if necessary.
 	pub fn process_step<F: FnMut(f32, MidiEvent) -> Result<(), ()>>(
 		&mut self,
 		pattern: &ArpeggioData,
 		time: u64,
+		max_notes: usize,
 		mut callback: F
 	) -> Result<(), ()> {
 		if self.chord_hold != self.chord_hold_old {
@@ -244,9 +519,17 @@ impl Arpeggiator {
 			}
 			self.chord_hold_old = self.chord_hold;
 		}
+		if self.latch != self.latch_old {
+			// the latched notes are not held down anymore
+			if !self.latch {
+				self.chord.clear();
+				self.chord_next_update_time = Some(time);
+			}
+			self.latch_old = self.latch;
+		}
 		if self.scale_base_override != self.scale_base_override_old {
 			if let Some(note) = self.scale_base_override {
-				self.stable_chord = scale_from(&self.scale, note);
+				self.set_stable_chord_from_scale(note);
 			}
 			else {
 				self.chord_next_update_time = Some(time);
@@ -255,40 +538,272 @@ impl Arpeggiator {
 		}
 		if let Some(chord_next_update_time) = self.chord_next_update_time {
 			if time >= chord_next_update_time {
-				self.stable_chord = self.chord.clone();
+				let mut chord = self.chord.clone();
+				self.chord_order.sort(&mut chord);
+				self.stable_chord = chord.iter().map(|n| n.0).collect();
+				self.stable_velocities = chord.iter().map(|n| n.1).collect();
 				self.chord_next_update_time = None;
 			}
 		}
 
 		let current_step = self.step % pattern.pattern.len(); // pattern length could have changed, in which case we need to do this modulo again
 		self.step = (self.step + 1) % pattern.pattern.len();
+		let loop_count = self.loop_count;
+		if self.step == 0 {
+			self.loop_count += 1;
+		}
+		let total_steps = self.total_steps;
+		self.total_steps += 1;
+
+		let mut notes_left = max_notes;
+		let n_entries = pattern.entries_at(current_step, total_steps).count();
+		for (position, entry) in pattern.entries_at(current_step, total_steps).enumerate() {
+			if notes_left == 0 {
+				break;
+			}
+			if !self.condition_met(entry.condition, loop_count) {
+				continue;
+			}
+			if entry.probability < 1.0 && self.random.next_f32() >= entry.probability {
+				continue;
+			}
 
-		for entry in pattern.pattern[current_step].iter() {
 			let length_modifier = (self.global_length_modifier
 				* (1.0 + (2.0 * entry.intensity - 1.0) * self.intensity_length_modifier_amount))
 				.clamp(0.0, 2.0);
-			let velocity = (self.global_velocity
-				* (0.5 + (entry.intensity - 0.5) * self.intensity_velocity_amount))
-				.clamp(0.0, 1.0);
 			let note_length = entry.actual_len(length_modifier);
-			if let Some(note) = pattern
+			let index = pattern
 				.repeat_mode
-				.get(&self.stable_chord, entry.note)
-				.map(|n| n.transpose(entry.transpose))
+				.index(self.stable_chord.len(), entry.note, loop_count);
+			if let Some((note, input_velocity)) = index
+				.map(|(i, transpose)| {
+					let input_velocity = self
+						.stable_velocities
+						.get(i)
+						.copied()
+						.unwrap_or(DEFAULT_INPUT_VELOCITY);
+					let (note, octaves) = self.scale_degree(i, entry.degrees);
+					note.transpose(transpose + 12 * octaves + entry.transpose)
+						.map(|note| (note, input_velocity as f32 / 127.0))
+				})
 				.flatten()
 			{
-				callback(note_length, MidiEvent::NoteOff(note, Channel(0)))?;
-				callback(
-					0.0,
-					MidiEvent::NoteOn(note, (127.0 * velocity) as u8, Channel(0))
-				)?;
+				let step_velocity = self.global_velocity
+					* (0.5 + (entry.intensity - 0.5) * self.intensity_velocity_amount);
+				let velocity = match self.velocity_source {
+					VelocitySource::Step => step_velocity,
+					VelocitySource::Input => self.global_velocity * input_velocity,
+					VelocitySource::Blend => {
+						step_velocity
+							* (1.0 - self.input_velocity_amount
+								+ self.input_velocity_amount * input_velocity)
+					}
+				}
+				.clamp(0.0, 1.0);
+
+				// the repeats divide the note evenly, each one shortened like the whole note.
+				// If there's not enough room for all, the remaining entries get an equal share.
+				let ratchets = entry
+					.ratchets
+					.clamp(1, MAX_RATCHETS)
+					.min((notes_left / (n_entries - position)).max(1) as u32);
+				notes_left -= ratchets as usize;
+				let spacing = entry.len_steps as f32 / ratchets as f32;
+				for i in 0..ratchets {
+					let velocity = (velocity * entry.ratchet_velocity(i)
+						+ self.humanize_velocity * (2.0 * self.humanize() - 1.0))
+						.clamp(0.0, 1.0);
+					let offset =
+						entry.delay + i as f32 * spacing + self.humanize_timing * self.humanize();
+					callback(
+						offset + note_length / ratchets as f32,
+						MidiEvent::NoteOff(note, DEFAULT_RELEASE_VELOCITY, Channel(0))
+					)?;
+					callback(
+						offset,
+						MidiEvent::NoteOn(note, ((127.0 * velocity) as u8).max(1), Channel(0))
+					)?;
+				}
 			}
 		}
 		Ok(())
 	}
-	pub fn reset(&mut self) { self.step = 0; }
+	/// Returns a random number in `0.0..1.0` if humanizing is enabled, or 0.5 otherwise.
+	/// This keeps the random sequence of the step probabilities unchanged without humanizing.
+	fn humanize(&mut self) -> f32 {
+		if self.humanize_velocity > 0.0 || self.humanize_timing > 0.0 {
+			self.random.next_f32()
+		}
+		else {
+			0.5
+		}
+	}
+
+	/// Returns the note `degrees` scale degrees away from `stable_chord[i]`, as a note of
+	/// `stable_chord` and the number of octaves to shift it by.
+	fn scale_degree(&self, i: usize, degrees: i32) -> (Note, i32) {
+		if self.scale.is_empty() || degrees == 0 {
+			return (self.stable_chord[i], 0);
+		}
+		let degree = i as isize + degrees as isize;
+		let len = self.stable_chord.len();
+		(
+			self.stable_chord[modulo(degree, len)],
+			div_floor(degree, len) as i32
+		)
+	}
+
+	fn condition_met(&mut self, condition: Condition, loop_count: usize) -> bool {
+		use Condition::*;
+		let result = match condition {
+			Always => return true,
+			Ratio(a, b) => loop_count % b.max(1) as usize + 1 == a as usize,
+			First => loop_count == 0,
+			NotFirst => loop_count != 0,
+			Fill => self.fill,
+			NotFill => !self.fill,
+			Previous => return self.previous_condition,
+			NotPrevious => return !self.previous_condition
+		};
+		self.previous_condition = result;
+		result
+	}
+
+	pub fn reset(&mut self) {
+		self.step = 0;
+		self.loop_count = 0;
+		self.total_steps = 0;
+	}
+
+	/// Moves to the position `total_steps` steps after the start, of which the last
+	/// `pattern_steps` were played in the current pattern of length `len`.
+	pub fn seek(&mut self, total_steps: usize, pattern_steps: usize, len: usize) {
+		self.step = pattern_steps % len;
+		self.loop_count = pattern_steps / len;
+		self.total_steps = total_steps;
+	}
 
 	pub fn step(&self) -> usize { self.step }
+
+	pub fn total_steps(&self) -> usize { self.total_steps }
+}
+
+#[derive(Copy, Clone, Default, Serialize, Deserialize)]
+pub struct MixerState {
+	pub muted: bool,
+	/// If any instance is soloed, only the soloed ones are audible
+	pub solo: bool,
+	/// Keep sending notes to the routed instances while not audible
+	pub route_when_muted: bool
+}
+
+impl MixerState {
+	pub fn audible(&self, any_solo: bool) -> bool { !self.muted && (self.solo || !any_solo) }
+}
+
+/// Decides when a newly selected pattern starts playing
+#[derive(Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum LaunchQuantization {
+	Immediate,
+	NextStep,
+	/// When the current pattern starts over
+	PatternEnd,
+	/// At the next multiple of this many steps since the start
+	Bar(usize)
+}
+
+impl Default for LaunchQuantization {
+	fn default() -> LaunchQuantization { LaunchQuantization::Immediate }
+}
+
+#[derive(Copy, Clone, Serialize, Deserialize)]
+pub struct ChainEntry {
+	pub pattern: usize,
+	/// How often the pattern is played before moving on
+	pub repeats: usize
+}
+
+/// A sequence of patterns that the arpeggiator plays one after another. An empty chain
+/// leaves the pattern selection to the user.
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct Chain {
+	pub entries: heapless::Vec<ChainEntry, 16>,
+	/// Keep playing the last entry instead of starting over
+	pub one_shot: bool,
+	#[serde(skip)]
+	position: usize,
+	/// Number of completed repeats of the current entry
+	#[serde(skip)]
+	repeat: usize
+}
+
+impl Chain {
+	/// The index of the entry that is currently playing
+	pub fn position(&self) -> Option<usize> {
+		if self.entries.is_empty() {
+			None
+		}
+		else {
+			Some(self.position.min(self.entries.len() - 1))
+		}
+	}
+
+	/// Returns to the first entry and returns its pattern.
+	pub fn reset(&mut self) -> Option<usize> {
+		self.position = 0;
+		self.repeat = 0;
+		self.entries.first().map(|entry| entry.pattern)
+	}
+
+	/// To be called at the end of each pass through the current pattern. Returns the pattern
+	/// of the next entry, if the chain moves on.
+	pub fn advance(&mut self) -> Option<usize> {
+		let position = self.position()?;
+		self.repeat += 1;
+		if self.repeat < self.entries[position].repeats {
+			return None;
+		}
+		self.repeat = 0;
+		self.position = if position + 1 < self.entries.len() {
+			position + 1
+		}
+		else if self.one_shot {
+			position
+		}
+		else {
+			0
+		};
+		Some(self.entries[self.position].pattern)
+	}
+
+	/// Moves to the position `steps` steps after the start. Returns the pattern to play and
+	/// the number of steps already played in it.
+	pub fn seek(
+		&mut self,
+		steps: usize,
+		pattern_len: impl Fn(usize) -> usize
+	) -> Option<(usize, usize)> {
+		self.reset()?;
+		let entry_len = |entry: &ChainEntry| pattern_len(entry.pattern) * entry.repeats.max(1);
+		let chain_len: usize = self.entries.iter().map(entry_len).sum();
+		let mut steps = if self.one_shot || chain_len == 0 {
+			steps
+		}
+		else {
+			steps % chain_len
+		};
+		for (position, entry) in self.entries.iter().enumerate() {
+			if steps < entry_len(entry) || position + 1 == self.entries.len() {
+				self.position = position;
+				// a one-shot chain stays on the last entry forever
+				let pattern_steps = steps % entry_len(entry).max(1);
+				self.repeat = pattern_steps / pattern_len(entry.pattern).max(1);
+				return Some((entry.pattern, pattern_steps));
+			}
+			steps -= entry_len(entry);
+		}
+		None
+	}
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -297,13 +812,71 @@ pub struct ArpeggiatorInstance {
 	pub patterns: [ArpeggioData; 8],
 	pub active_pattern: usize,
 	pub arp: Arpeggiator,
+	#[serde(default)]
+	pub in_channel: Channel,
+	/// Accept notes on all channels
+	#[serde(default)]
+	pub omni: bool,
+	#[serde(default)]
+	pub out_channel: Channel,
+	/// Controllers bound to the entries of `faders_mut()`
+	#[serde(default)]
+	pub cc_bindings: [Option<CcBinding>; N_FADERS],
+	/// How late every second step is played, as a fraction of a step
+	#[serde(default)]
+	pub swing: f32,
+	#[serde(default)]
+	pub chain: Chain,
+	#[serde(default)]
+	pub launch_quantization: LaunchQuantization,
+	#[serde(default)]
+	pub mixer: MixerState,
 
+	/// The pattern that will be switched to according to `launch_quantization`
+	#[serde(skip)]
+	queued_pattern: Option<usize>,
+	#[serde(skip)]
+	was_audible: bool,
 	#[serde(skip)]
 	tick_counter: u32,
 	#[serde(skip, default = "TempoDetector::new")]
 	tempo: TempoDetector,
 	#[serde(skip)]
-	pending_events: heapless::Vec<(u64, MidiEvent), 32>
+	pending_events: heapless::Vec<(u64, MidiEvent), PENDING_EVENTS>,
+	#[serde(skip)]
+	cc_takeover: [Takeover; N_FADERS]
+}
+
+pub const N_FADERS: usize = 8;
+
+const PENDING_EVENTS: usize = 128;
+/// Room in the pending events that the notes must leave for the controller messages
+/// that are passed through
+const RESERVED_PENDING_EVENTS: usize = 16;
+
+/// The parameters that can be controlled by the slider screen and via MIDI, with their
+/// ranges. Takes the single fields, either by value or by reference, so that callers can
+/// still borrow the remaining fields of the instance.
+pub fn faders<T>(
+	global_length_modifier: T,
+	swing: T,
+	intensity_length_modifier_amount: T,
+	humanize_timing: T,
+	global_velocity: T,
+	humanize_velocity: T,
+	intensity_velocity_amount: T,
+	input_velocity_amount: T
+) -> [Option<(T, RangeInclusive<f32>)>; N_FADERS] {
+	[
+		Some((global_length_modifier, 0.0..=2.0)),
+		Some((swing, 0.0..=0.5)),
+		Some((intensity_length_modifier_amount, 0.0..=2.0)),
+		Some((humanize_timing, 0.0..=0.5)),
+		Some((global_velocity, 0.0..=2.0)),
+		Some((humanize_velocity, 0.0..=0.5)),
+		Some((intensity_velocity_amount, 0.0..=2.0)),
+		Some((input_velocity_amount, 0.0..=1.0))
+	]
 }
 
 impl ArpeggiatorInstance {
@@ -311,10 +884,168 @@ impl ArpeggiatorInstance {
 		self.tempo.reset();
 		self.tick_counter = self.ticks_per_step - 1;
 		self.arp.reset();
+		if let Some(pattern) = self.chain.reset() {
+			self.set_active_pattern(pattern);
+		}
+	}
+
+	/// Drops all pending notes and moves their note offs to `timestamp`, so that no notes
+	/// keep hanging after the transport has stopped.
+	pub fn stop_transport(&mut self, timestamp: u64) {
+		self.pending_events
+			.retain(|(event_timestamp, event)| match event {
+				MidiEvent::NoteOn(..) => *event_timestamp < timestamp,
+				_ => true
+			});
+		for (event_timestamp, event) in self.pending_events.iter_mut() {
+			if let MidiEvent::NoteOff(..) = event {
+				*event_timestamp = timestamp.min(*event_timestamp);
+			}
+		}
+	}
+
+	pub fn continue_transport(&mut self) {
+		// the time since the last beat is meaningless after a pause
+		self.tempo.reset();
+	}
+
+	/// Moves to the position `clocks` MIDI clocks after the song start. The next incoming
+	/// clock is considered to be the clock at that position.
+	pub fn seek(&mut self, clocks: u32) {
+		self.tempo.reset();
+		self.tick_counter = (clocks + self.ticks_per_step - 1) % self.ticks_per_step;
+		let steps = (clocks + self.ticks_per_step - 1) as usize / self.ticks_per_step as usize;
+		let patterns = &self.patterns;
+		let pattern_steps = match self.chain.seek(steps, |pattern| {
+			patterns.get(pattern).map_or(0, |p| p.pattern.len())
+		}) {
+			Some((pattern, pattern_steps)) => {
+				self.set_active_pattern(pattern);
+				pattern_steps
+			}
+			None => steps
+		};
+		self.arp
+			.seek(steps, pattern_steps, self.active_pattern().pattern.len());
 	}
 
 	pub fn active_pattern(&self) -> &ArpeggioData { &self.patterns[self.active_pattern] }
 
+	/// Switches to `pattern` according to `launch_quantization`. Selecting the active pattern
+	/// cancels a queued switch.
+	pub fn queue_pattern(&mut self, pattern: usize) {
+		if pattern >= self.patterns.len() {
+			return;
+		}
+		if self.launch_quantization == LaunchQuantization::Immediate {
+			self.active_pattern = pattern;
+			self.queued_pattern = None;
+		}
+		else if pattern == self.active_pattern {
+			self.queued_pattern = None;
+		}
+		else {
+			self.queued_pattern = Some(pattern);
+		}
+	}
+
+	pub fn queued_pattern(&self) -> Option<usize> { self.queued_pattern }
+
+	fn launch_queued_pattern(&mut self) {
+		use LaunchQuantization::*;
+		if let Some(pattern) = self.queued_pattern {
+			let due = match self.launch_quantization {
+				Immediate | NextStep => true,
+				PatternEnd => self.arp.step() == 0,
+				Bar(steps) => self.arp.total_steps() % steps.max(1) == 0
+			};
+			if due {
+				self.active_pattern = pattern;
+				self.queued_pattern = None;
+				if let PatternEnd | Bar(_) = self.launch_quantization {
+					let len = self.active_pattern().pattern.len();
+					self.arp.seek(self.arp.total_steps(), 0, len);
+				}
+			}
+		}
+	}
+
+	/// Selects the pattern, ignoring invalid ones that were e.g. loaded from a file
+	fn set_active_pattern(&mut self, pattern: usize) {
+		if pattern < self.patterns.len() {
+			self.active_pattern = pattern;
+		}
+	}
+
+	/// Returns true if the instance was audible the last time this was called, but isn't anymore
+	pub fn just_silenced(&mut self, any_solo: bool) -> bool {
+		let audible = self.mixer.audible(any_solo);
+		let silenced = self.was_audible && !audible;
+		self.was_audible = audible;
+		silenced
+	}
+
+	pub fn listens_to(&self, channel: Channel) -> bool { self.omni || channel == self.in_channel }
+
+	/// The parameters that can be controlled by the slider screen and via MIDI
+	pub fn faders_mut(&mut self) -> [Option<(&mut f32, RangeInclusive<f32>)>; N_FADERS] {
+		faders(
+			&mut self.arp.global_length_modifier,
+			&mut self.swing,
+			&mut self.arp.intensity_length_modifier_amount,
+			&mut self.arp.humanize_timing,
+			&mut self.arp.global_velocity,
+			&mut self.arp.humanize_velocity,
+			&mut self.arp.intensity_velocity_amount,
+			&mut self.arp.input_velocity_amount
+		)
+	}
+
+	pub fn faders(&self) -> [Option<(f32, RangeInclusive<f32>)>; N_FADERS] {
+		faders(
+			self.arp.global_length_modifier,
+			self.swing,
+			self.arp.intensity_length_modifier_amount,
+			self.arp.humanize_timing,
+			self.arp.global_velocity,
+			self.arp.humanize_velocity,
+			self.arp.intensity_velocity_amount,
+			self.arp.input_velocity_amount
+		)
+	}
+
+	/// Binds `controller` to the given fader, removing any other binding of it.
+	pub fn learn_cc(&mut self, fader: usize, controller: u8) {
+		for binding in self.cc_bindings.iter_mut() {
+			if binding.map(|b| b.controller) == Some(controller) {
+				*binding = None;
+			}
+		}
+		let mode = self.cc_bindings[fader]
+			.map(|b| b.mode)
+			.unwrap_or(CcMode::SoftTakeover);
+		self.cc_bindings[fader] = Some(CcBinding { controller, mode });
+		self.cc_takeover[fader] = Takeover::default();
+	}
+
+	/// Applies a control change to the fader it is bound to. Returns false if no fader
+	/// is bound to `controller`.
+	pub fn control_change(&mut self, controller: u8, value: u8) -> bool {
+		let bindings = self.cc_bindings;
+		let mut takeover = self.cc_takeover;
+		let mut handled = false;
+		for (i, fader) in self.faders_mut().iter_mut().enumerate() {
+			if let (Some(binding), Some((fader_value, range))) = (bindings[i], fader) {
+				if binding.controller == controller {
+					binding.apply(&mut takeover[i], value, fader_value, range.clone());
+					handled = true;
+				}
+			}
+		}
+		self.cc_takeover = takeover;
+		handled
+	}
+
 	pub fn tick_clock(&mut self, timestamp: u64) {
 		self.tick_counter += 1;
 		if self.tick_counter >= self.ticks_per_step {
@@ -322,21 +1053,36 @@ impl ArpeggiatorInstance {
 
 			self.tempo.beat(timestamp);
 			let time_per_beat = self.tempo.time_per_beat();
+			self.launch_queued_pattern();
+			let pattern = &self.patterns[self.active_pattern];
+			let swing = if self.arp.total_steps() % 2 == 1 {
+				self.swing
+			}
+			else {
+				0.0
+			};
 
 			let pending_events = &mut self.pending_events;
+			let max_notes =
+				(PENDING_EVENTS - RESERVED_PENDING_EVENTS).saturating_sub(pending_events.len()) / 2;
 			self.arp
-				.process_step(
-					&self.patterns[self.active_pattern],
-					timestamp,
-					|timestamp_steps, event| {
-						let event_timestamp =
-							timestamp + (time_per_beat as f32 * timestamp_steps) as u64;
-						pending_events
-							.push((event_timestamp, event))
-							.map_err(|_| ())
-					}
-				)
-				.expect("process_step failed (buffer overflow?)");
+				.process_step(pattern, timestamp, max_notes, |timestamp_steps, event| {
+					let event_timestamp =
+						timestamp + (time_per_beat as f32 * (swing + timestamp_steps)) as u64;
+					pending_events
+						.push((event_timestamp, event))
+						.map_err(|_| ())
+				})
+				.ok();
+
+			if self.arp.step() == 0 {
+				if let Some(pattern) = self.chain.advance() {
+					self.set_active_pattern(pattern);
+					// conditions count the passes through the new pattern
+					let len = self.active_pattern().pattern.len();
+					self.arp.seek(self.arp.total_steps(), 0, len);
+				}
+			}
 		}
 	}
 
@@ -345,13 +1091,19 @@ impl ArpeggiatorInstance {
 			.rem_euclid(self.active_pattern().pattern.len() as f32)
 	}
 
+	/// Like `currently_playing_tick()`, but counted since the start, for the rows that
+	/// loop with their own length
+	pub fn currently_playing_total_tick(&self) -> f32 {
+		self.arp.total_steps() as f32 - 1.0 + self.tick_counter as f32 / self.ticks_per_step as f32
+	}
+
 	pub fn add_pending_event(&mut self, timestamp: u64, event: MidiEvent) -> Result<(), ()> {
 		self.pending_events.push((timestamp, event)).map_err(|_| ())
 	}
 
 	pub fn pending_note_offs<'a>(&'a self) -> impl Iterator<Item = Note> + 'a {
 		self.pending_events.iter().filter_map(|tup| match tup.1 {
-			MidiEvent::NoteOff(note, _) => Some(note),
+			MidiEvent::NoteOff(note, ..) => Some(note),
 			_ => None
 		})
 	}
@@ -395,7 +1147,8 @@ impl ArpeggiatorInstance {
 				heapless::Vec::new()
 			])
 			.unwrap(),
-			repeat_mode: RepeatMode::Repeat(12)
+			repeat_mode: RepeatMode::Repeat(12),
+			row_lengths: heapless::Vec::new()
 		};
 		ArpeggiatorInstance {
 			ticks_per_step: 6,
@@ -412,6 +1165,17 @@ impl ArpeggiatorInstance {
 				pattern.clone()
 			],
 			active_pattern: 0,
+			in_channel: Channel(0),
+			omni: false,
+			out_channel: Channel(0),
+			cc_bindings: [None; N_FADERS],
+			swing: 0.0,
+			chain: Chain::default(),
+			launch_quantization: LaunchQuantization::Immediate,
+			queued_pattern: None,
+			mixer: MixerState::default(),
+			was_audible: false,
+			cc_takeover: [Takeover::default(); N_FADERS],
 			tempo: TempoDetector::new(),
 			pending_events: heapless::Vec::new()
 		}
@@ -433,6 +1197,8 @@ fn div_floor(numerator: isize, denominator: usize) -> isize {
 
 #[cfg(test)]
 mod tests {
+	use super::{Arpeggiator, ArpeggioData, MidiEvent, Note, RepeatMode};
+
 	fn assert_slice_eq<T: PartialEq + std::fmt::Debug>(a: &[T], b: &[T]) {
 		assert!(a.len() == b.len());
 		a.iter()
@@ -443,6 +1209,44 @@ mod tests {
 			});
 	}
 
+	/// An empty pattern with `len` steps
+	fn pattern(repeat_mode: RepeatMode, len: usize) -> ArpeggioData {
+		let mut pattern = ArpeggioData {
+			repeat_mode,
+			pattern: heapless::Vec::new(),
+			row_lengths: heapless::Vec::new()
+		};
+		pattern.pattern.resize_default(len).unwrap();
+		pattern
+	}
+
+	/// Plays `steps` steps and returns the time, note and velocity of every note on
+	fn note_ons(
+		arp: &mut Arpeggiator,
+		pattern: &ArpeggioData,
+		steps: usize
+	) -> Vec<(f32, Note, u8)> {
+		let mut result = Vec::new();
+		for _ in 0..steps {
+			arp.process_step(pattern, 0, usize::MAX, |time, event| {
+				if let MidiEvent::NoteOn(note, velocity, _) = event {
+					result.push((time, note, velocity));
+				}
+				Ok(())
+			})
+			.unwrap();
+		}
+		result
+	}
+
+	/// Like `note_ons`, but only returns the played notes
+	fn notes(arp: &mut Arpeggiator, pattern: &ArpeggioData, steps: usize) -> Vec<u8> {
+		note_ons(arp, pattern, steps)
+			.iter()
+			.map(|(_, note, _)| note.0)
+			.collect()
+	}
+
 	#[test]
 	pub fn scale_from() {
 		use super::scale_from;
@@ -467,4 +1271,599 @@ mod tests {
 
 		assert_slice_eq(&scale_from::<32>(&scale, Note(31)), &[]);
 	}
+
+	#[test]
+	pub fn repeat_modes() {
+		use super::RepeatMode;
+		use super::RepeatMode::*;
+
+		let indices = |mode: RepeatMode, loop_count| {
+			(-2..6)
+				.map(|i| mode.index(3, i, loop_count))
+				.collect::<Vec<_>>()
+		};
+
+		assert_eq!(
+			indices(Clamp, 0),
+			[1, 2, 0, 1, 2, 2, 2, 2].map(|i| Some((i, 0)))
+		);
+		assert_eq!(
+			indices(Mirror, 0),
+			[2, 1, 0, 1, 2, 1, 0, 1].map(|i| Some((i, 0)))
+		);
+		assert_eq!(
+			indices(Repeat(12), 0),
+			[
+				(1, -12),
+				(2, -12),
+				(0, 0),
+				(1, 0),
+				(2, 0),
+				(0, 12),
+				(1, 12),
+				(2, 12)
+			]
+			.map(Some)
+		);
+		assert_eq!(
+			indices(Skip, 0),
+			[
+				None,
+				None,
+				Some((0, 0)),
+				Some((1, 0)),
+				Some((2, 0)),
+				None,
+				None,
+				None
+			]
+		);
+		assert_eq!(Skip.index(0, 0, 0), None);
+
+		// every loop plays each chord note once per chord length, in an order fixed for the loop
+		for loop_count in 0..4 {
+			let notes = indices(Random, loop_count);
+			assert_eq!(notes, indices(Random, loop_count));
+			let mut sorted = notes[2..5].iter().map(|i| i.unwrap().0).collect::<Vec<_>>();
+			sorted.sort();
+			assert_eq!(sorted, [0, 1, 2]);
+			assert_eq!(notes[2..5], notes[5..8]);
+		}
+		assert!((0..8).any(|loop_count| indices(Random, loop_count) != indices(Random, 0)));
+	}
+
+	#[test]
+	pub fn filter_beyond_pattern_end() {
+		use super::*;
+
+		let mut pattern = pattern(RepeatMode::Repeat(12), 1);
+		pattern.set(0, Entry::default()).unwrap();
+
+		assert!(pattern.filter(0, 0).count() == 1);
+		assert!(pattern.filter(1, 0).count() == 0);
+		assert!(pattern.filter_mut(64, 0).count() == 0);
+	}
+
+	#[test]
+	pub fn input_velocity() {
+		use super::*;
+
+		let mut pattern = pattern(RepeatMode::Repeat(12), 1);
+		pattern.set(0, Entry::default()).unwrap();
+
+		let mut arp = Arpeggiator::new();
+		arp.note_on(Note(60), 64, 0);
+
+		let mut velocities = Vec::new();
+		for source in [
+			VelocitySource::Step,
+			VelocitySource::Input,
+			VelocitySource::Blend
+		] {
+			arp.velocity_source = source;
+			arp.input_velocity_amount = 0.5;
+			velocities.extend(
+				note_ons(&mut arp, &pattern, 1)
+					.iter()
+					.map(|note_on| note_on.2)
+			);
+		}
+		assert_slice_eq(&velocities, &[127, 64, 95]);
+	}
+
+	#[test]
+	pub fn latch() {
+		use super::*;
+
+		let mut pattern = pattern(RepeatMode::Skip, 3);
+		for note in 0..3 {
+			let entry = Entry {
+				note,
+				..Entry::default()
+			};
+			pattern.set(note as usize, entry).unwrap();
+		}
+		let play = |arp: &mut Arpeggiator| notes(arp, &pattern, 3);
+
+		let mut arp = Arpeggiator::new();
+		arp.latch = true;
+		for note in [60, 64, 67, 64] {
+			arp.note_on(Note(note), 100, 0);
+			arp.note_off(Note(note), 0);
+		}
+		assert_slice_eq(&play(&mut arp), &[60, 67]);
+
+		// routed notes neither unlatch the held ones nor stay latched themselves
+		arp.routed_note_on(Note(60), 100, 0);
+		arp.routed_note_on(Note(64), 100, 0);
+		assert_slice_eq(&play(&mut arp), &[60, 64, 67]);
+		arp.routed_note_off(Note(64), 0);
+		assert_slice_eq(&play(&mut arp), &[60, 67]);
+
+		arp.latch = false;
+		assert_slice_eq(&play(&mut arp), &[]);
+	}
+
+	#[test]
+	pub fn chord_order() {
+		use super::*;
+
+		let mut pattern = pattern(RepeatMode::Repeat(12), 3);
+		for note in 0..3 {
+			let entry = Entry {
+				note,
+				..Entry::default()
+			};
+			pattern.set(note as usize, entry).unwrap();
+		}
+
+		for (order, expected) in [
+			(ChordOrder::Ascending, [60, 64, 67]),
+			(ChordOrder::Descending, [67, 64, 60]),
+			(ChordOrder::Arrival, [64, 60, 67])
+		] {
+			let mut arp = Arpeggiator::new();
+			arp.chord_order = order;
+			arp.note_on(Note(64), 100, 0);
+			arp.note_on(Note(60), 100, 0);
+			arp.note_on(Note(67), 100, 0);
+			arp.note_on(Note(62), 100, 0);
+			arp.note_off(Note(62), 0);
+
+			assert_slice_eq(&notes(&mut arp, &pattern, 3), &expected);
+		}
+	}
+
+	#[test]
+	pub fn probability() {
+		use super::*;
+
+		let mut pattern = pattern(RepeatMode::Repeat(12), 1);
+		let entries = [
+			Entry {
+				probability: 0.0,
+				..Entry::default()
+			},
+			Entry {
+				note: 1,
+				probability: 0.5,
+				..Entry::default()
+			},
+			Entry {
+				note: 2,
+				..Entry::default()
+			}
+		];
+		for entry in entries {
+			pattern.set(0, entry).unwrap();
+		}
+
+		let mut arp = Arpeggiator::new();
+		arp.seed_random(42);
+		arp.note_on(Note(60), 100, 0);
+		arp.note_on(Note(64), 100, 0);
+		arp.note_on(Note(67), 100, 0);
+
+		let mut counts = [0; 3];
+		for note in notes(&mut arp, &pattern, 1000) {
+			counts[[60, 64, 67].iter().position(|n| *n == note).unwrap()] += 1;
+		}
+		assert!(counts[0] == 0);
+		assert!((400..600).contains(&counts[1]));
+		assert!(counts[2] == 1000);
+	}
+
+	#[test]
+	pub fn ratchets() {
+		use super::*;
+
+		let mut pattern = pattern(RepeatMode::Repeat(12), 1);
+		let entry = Entry {
+			len_steps: 2,
+			ratchets: 4,
+			ratchet_ramp: -1.0,
+			..Entry::default()
+		};
+		pattern.set(0, entry).unwrap();
+
+		let mut arp = Arpeggiator::new();
+		arp.note_on(Note(60), 100, 0);
+
+		let mut note_ons = Vec::new();
+		arp.process_step(&pattern, 0, usize::MAX, |time, event| {
+			match event {
+				MidiEvent::NoteOn(_, velocity, _) => note_ons.push((time, velocity)),
+				MidiEvent::NoteOff(..) => assert!(time.fract() != 0.0 && time.fract() != 0.5),
+				_ => ()
+			}
+			Ok(())
+		})
+		.unwrap();
+		assert_slice_eq(&note_ons, &[(0.0, 127), (0.5, 95), (1.0, 63), (1.5, 31)]);
+
+		// many ratchets on many notes do not overflow the event queue
+		let mut instance = ArpeggiatorInstance::new();
+		for note in 60..68 {
+			instance.arp.note_on(Note(note), 100, 0);
+		}
+		for note in 0..16 {
+			instance.patterns[0]
+				.set(
+					0,
+					Entry {
+						note,
+						ratchets: MAX_RATCHETS,
+						..Entry::default()
+					}
+				)
+				.unwrap();
+		}
+		for _ in 0..6 {
+			instance.tick_clock(0);
+		}
+		let reserved = instance.pending_events.capacity() - instance.pending_events.len();
+		assert!(reserved >= RESERVED_PENDING_EVENTS);
+
+		// every entry is played, with as many ratchets as fit, and no note off is lost
+		let mut events = Vec::new();
+		instance.process_pending_events(u64::MAX, |pending| events.extend_from_slice(pending));
+		let note_ons: Vec<_> = events
+			.iter()
+			.filter_map(|(_, event)| match event {
+				MidiEvent::NoteOn(note, ..) => Some(*note),
+				_ => None
+			})
+			.collect();
+		let note_offs = events
+			.iter()
+			.filter(|(_, event)| matches!(event, MidiEvent::NoteOff(..)))
+			.count();
+		assert!(note_ons.len() <= (PENDING_EVENTS - RESERVED_PENDING_EVENTS) / 2);
+		assert_eq!(note_offs, note_ons.len());
+		let pitches: std::collections::BTreeSet<_> = note_ons.iter().map(|note| note.0).collect();
+		assert!(pitches.into_iter().eq((60..68).chain(72..80)));
+		assert!(note_ons.len() > 16);
+	}
+
+	#[test]
+	pub fn swing_and_delay() {
+		use super::*;
+
+		let mut instance = ArpeggiatorInstance::new();
+		instance.arp.note_on(Note(60), 100, 0);
+		instance.swing = 0.25;
+		// an odd pattern length, to check that the swing does not restart with the pattern
+		instance.patterns[0].pattern.truncate(3);
+		for step in 0..3 {
+			instance.patterns[0].pattern[step].clear();
+			instance.patterns[0]
+				.set(
+					step,
+					Entry {
+						delay: if step == 2 { 0.5 } else { 0.0 },
+						..Entry::default()
+					}
+				)
+				.unwrap();
+		}
+
+		// one step every 6000 frames
+		instance.restart_transport();
+		let mut events = Vec::new();
+		for tick in 0..6 * 6 {
+			instance.tick_clock(tick * 1000);
+			instance.process_pending_events(u64::MAX, |pending| events.extend_from_slice(pending));
+		}
+		let note_ons: Vec<_> = events
+			.iter()
+			.filter(|(_, event)| matches!(event, MidiEvent::NoteOn(..)))
+			.map(|(time, _)| *time)
+			.collect();
+		let note_offs: Vec<_> = events
+			.iter()
+			.filter(|(_, event)| matches!(event, MidiEvent::NoteOff(..)))
+			.map(|(time, _)| *time)
+			.collect();
+
+		// every odd step is a quarter step late, the third step of the pattern half a step
+		assert_slice_eq(&note_ons, &[0, 7500, 15000, 19500, 24000, 34500]);
+		// the note length stays the same. The first step is shorter, because the tempo was
+		// not known yet
+		let lengths: Vec<_> = note_ons
+			.iter()
+			.zip(note_offs.iter())
+			.map(|(on, off)| off - on)
+			.collect();
+		assert!(lengths[1..].iter().all(|length| *length == lengths[1]));
+	}
+
+	#[test]
+	pub fn scale_degrees() {
+		use super::*;
+
+		let mut pattern = pattern(RepeatMode::Repeat(12), 4);
+		for (step, &(note, degrees)) in [(0, 2), (0, -1), (5, 2), (8, 0)].iter().enumerate() {
+			let entry = Entry {
+				note,
+				degrees,
+				..Entry::default()
+			};
+			pattern.set(step, entry).unwrap();
+		}
+
+		let mut arp = Arpeggiator::new();
+		arp.scale = [0, 2, 4, 5, 7, 9, 11].iter().map(|n| Note(*n)).collect();
+		arp.note_on(Note(60), 100, 0);
+
+		assert_slice_eq(&notes(&mut arp, &pattern, 4), &[64, 59, 72, 74]);
+	}
+
+	#[test]
+	pub fn seek() {
+		use super::ArpeggiatorInstance;
+
+		let mut instance = ArpeggiatorInstance::new();
+		instance.seek(18); // song position 3 with 6 ticks per step
+		instance.tick_clock(0);
+		assert!(instance.arp.step() == 4);
+
+		instance.seek(20);
+		for _ in 0..4 {
+			instance.tick_clock(0);
+		}
+		assert!(instance.arp.step() == 4);
+		instance.tick_clock(0);
+		assert!(instance.arp.step() == 5);
+	}
+
+	#[test]
+	pub fn chain() {
+		use super::*;
+
+		let mut instance = ArpeggiatorInstance::new();
+		instance.patterns[1].pattern.truncate(4);
+		instance.chain.entries = heapless::Vec::from_slice(&[
+			ChainEntry {
+				pattern: 1,
+				repeats: 2
+			},
+			ChainEntry {
+				pattern: 0,
+				repeats: 1
+			}
+		])
+		.unwrap();
+
+		let mut patterns = Vec::new();
+		instance.restart_transport();
+		for _ in 0..20 * 6 {
+			instance.tick_clock(0);
+			patterns.push(instance.active_pattern);
+		}
+		let patterns: Vec<_> = patterns.chunks(6).map(|step| step[0]).collect();
+		// the active pattern changes when the last step of the previous one was played
+		assert_slice_eq(
+			&patterns,
+			&[1, 1, 1, 1, 1, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 1]
+		);
+		assert_eq!(instance.chain.position(), Some(0));
+
+		instance.seek(6 * 9);
+		assert_eq!(instance.active_pattern, 0);
+		assert_eq!(instance.arp.step(), 1);
+		assert_eq!(instance.chain.position(), Some(1));
+
+		instance.chain.one_shot = true;
+		instance.seek(6 * 37);
+		assert_eq!(instance.active_pattern, 0);
+		assert_eq!(instance.arp.step(), 5);
+	}
+
+	#[test]
+	pub fn launch_quantization() {
+		use super::*;
+
+		let mut instance = ArpeggiatorInstance::new();
+		instance.patterns[1].pattern.truncate(4);
+		instance.restart_transport();
+		let step = |instance: &mut ArpeggiatorInstance| {
+			for _ in 0..6 {
+				instance.tick_clock(0);
+			}
+			(instance.active_pattern, instance.arp.step())
+		};
+
+		step(&mut instance);
+		instance.launch_quantization = LaunchQuantization::NextStep;
+		instance.queue_pattern(1);
+		assert_eq!(instance.queued_pattern(), Some(1));
+		assert_eq!(step(&mut instance), (1, 2));
+
+		instance.launch_quantization = LaunchQuantization::PatternEnd;
+		instance.queue_pattern(0);
+		assert_eq!(step(&mut instance), (1, 3));
+		assert_eq!(step(&mut instance), (1, 0));
+		assert_eq!(step(&mut instance), (0, 1));
+
+		// 5 steps were played so far, the next bar starts after 8
+		instance.launch_quantization = LaunchQuantization::Bar(4);
+		instance.queue_pattern(1);
+		for _ in 0..3 {
+			assert_eq!(step(&mut instance).0, 0);
+		}
+		assert_eq!(step(&mut instance), (1, 1));
+
+		instance.queue_pattern(0);
+		instance.queue_pattern(1);
+		assert_eq!(instance.queued_pattern(), None);
+	}
+
+	#[test]
+	pub fn mute_solo() {
+		use super::*;
+
+		let mut instance = ArpeggiatorInstance::new();
+		assert!(!instance.just_silenced(false));
+		instance.mixer.muted = true;
+		assert!(instance.just_silenced(false));
+		assert!(!instance.just_silenced(false));
+
+		instance.mixer.muted = false;
+		assert!(!instance.just_silenced(false));
+		assert!(instance.just_silenced(true));
+		instance.mixer.solo = true;
+		assert!(instance.mixer.audible(true));
+	}
+
+	#[test]
+	pub fn row_lengths() {
+		use super::*;
+
+		let mut pattern = pattern(RepeatMode::Repeat(12), 4);
+		for note in 0..2 {
+			pattern
+				.set(
+					0,
+					Entry {
+						note,
+						..Entry::default()
+					}
+				)
+				.unwrap();
+		}
+		// playing the second row on the fourth step shows that it has a length of 3
+		pattern.set(3, Entry::default()).unwrap();
+		pattern
+			.set(
+				3,
+				Entry {
+					note: 1,
+					..Entry::default()
+				}
+			)
+			.unwrap();
+		pattern.set_row_len(1, Some(3));
+
+		let mut arp = Arpeggiator::new();
+		arp.note_on(Note(60), 100, 0);
+		arp.note_on(Note(64), 100, 0);
+
+		let played: Vec<_> = (0..8).map(|_| notes(&mut arp, &pattern, 1)).collect();
+		assert_slice_eq(
+			&played,
+			&[
+				vec![60, 64],
+				vec![],
+				vec![],
+				vec![60, 64],
+				vec![60],
+				vec![],
+				vec![64],
+				vec![60]
+			]
+		);
+
+		// as loaded from a broken save file
+		pattern.row_lengths[0].1 = 0;
+		assert_slice_eq(&notes(&mut arp, &pattern, 2), &[60, 64, 64]);
+	}
+
+	#[test]
+	pub fn conditions() {
+		use super::*;
+
+		let mut pattern = pattern(RepeatMode::Repeat(12), 1);
+		let entries = [
+			Entry {
+				condition: Condition::Ratio(2, 3),
+				..Entry::default()
+			},
+			Entry {
+				note: 1,
+				condition: Condition::NotPrevious,
+				..Entry::default()
+			},
+			Entry {
+				note: 2,
+				condition: Condition::Fill,
+				..Entry::default()
+			}
+		];
+		for entry in entries {
+			pattern.set(0, entry).unwrap();
+		}
+
+		let mut arp = Arpeggiator::new();
+		arp.note_on(Note(60), 100, 0);
+		arp.note_on(Note(64), 100, 0);
+		arp.note_on(Note(67), 100, 0);
+
+		let mut played = Vec::new();
+		for i in 0..6 {
+			arp.fill = i == 4;
+			played.push(notes(&mut arp, &pattern, 1));
+		}
+		assert_slice_eq(
+			&played,
+			&[
+				vec![64],
+				vec![60],
+				vec![64],
+				vec![64],
+				vec![60, 67],
+				vec![64]
+			]
+		);
+	}
+
+	#[test]
+	pub fn humanize() {
+		use super::*;
+
+		let mut pattern = pattern(RepeatMode::Repeat(12), 1);
+		pattern.set(0, Entry::default()).unwrap();
+
+		let mut arp = Arpeggiator::new();
+		arp.note_on(Note(60), 100, 0);
+
+		let plain = note_ons(&mut arp, &pattern, 16);
+		assert!(plain.iter().all(|note_on| *note_on == plain[0]));
+
+		arp.humanize_velocity = 0.25;
+		arp.humanize_timing = 0.25;
+		let humanized = note_ons(&mut arp, &pattern, 16);
+		assert!(humanized.iter().any(|note_on| *note_on != humanized[0]));
+		for (time, _, velocity) in humanized {
+			assert!((0.0..=0.25).contains(&time));
+			assert!((velocity as i32 - plain[0].2 as i32).abs() <= 32);
+		}
+
+		// a velocity of 0 would be a note off
+		arp.note_off(Note(60), 0);
+		arp.note_on(Note(60), 1, 0);
+		arp.velocity_source = VelocitySource::Input;
+		assert!(note_ons(&mut arp, &pattern, 16)
+			.iter()
+			.all(|&(_, _, velocity)| velocity > 0));
+	}
 }
@@ -0,0 +1,384 @@
+// this file is part of arpfisch. For copyright and licensing details, see main.rs
+
+use crate::application::ArpApplication;
+use crate::driver::buffered::FrameBuffers;
+use crate::driver::{TimestampedMidiEvent, TimestampedRawMidiEvent, SAMPLE_RATE};
+use crate::midi::{is_sysex, MidiEvent};
+use alsa::seq::{self, Addr, EventType, PortCap, PortInfo, PortSubscribe, PortType, Seq};
+use std::ffi::CString;
+use std::time::Duration;
+
+const PERIOD_SIZE: u32 = 256;
+/// Output events are scheduled on the queue this many periods after the period they
+/// were generated in. One period would match JACK's latency, the second one gives
+/// us headroom for the time spent processing.
+const LATENCY_PERIODS: u64 = 2;
+
+// The ALSA sequencer has no sample clock, so we emulate one using the queue's real time.
+fn frames_to_duration(frames: u64) -> Duration {
+	Duration::from_nanos((frames as u128 * 1_000_000_000 / SAMPLE_RATE as u128) as u64)
+}
+
+fn duration_to_frames(duration: Duration) -> u64 {
+	(duration.as_nanos() * SAMPLE_RATE as u128 / 1_000_000_000) as u64
+}
+
+pub struct AlsaDriver {
+	seq: Seq,
+	queue: i32,
+	ui_in_port: i32,
+	ui_out_port: i32,
+	periods: u64,
+
+	arp_in_ports: Vec<i32>,
+	arp_out_ports: Vec<i32>,
+
+	encoder: seq::MidiEvent,
+	decoder: seq::MidiEvent,
+	buffers: FrameBuffers,
+	/// Events that were received while processing, but belong to the next period.
+	/// Contains the destination port, the time stamp in frames and the raw bytes.
+	late_events: Vec<(i32, u64, Vec<u8>)>,
+	/// SysEx messages of this period, which are passed through to the arpeggiators'
+	/// outputs. Contains the arpeggiator, the time stamp and the raw bytes.
+	sysex_thru: Vec<(usize, u32, Vec<u8>)>,
+
+	application: Box<ArpApplication>
+}
+
+impl AlsaDriver {
+	pub fn run(name: &str, application: Box<ArpApplication>) {
+		let seq = Seq::open(None, None, true).expect("Failed to open the ALSA sequencer");
+		let mut alsa_driver = AlsaDriver::new_with_seq(name, application, seq)
+			.expect("Failed to set up the ALSA sequencer client");
+
+		alsa_driver.autoconnect();
+
+		loop {
+			alsa_driver.wait_for_period_end();
+			alsa_driver.process_period();
+		}
+	}
+
+	pub fn new_with_seq(
+		name: &str,
+		application: Box<ArpApplication>,
+		seq: Seq
+	) -> alsa::Result<AlsaDriver> {
+		seq.set_client_name(&CString::new(name).unwrap())?;
+		let queue = seq.alloc_named_queue(&CString::new(name).unwrap())?;
+
+		let input_caps = PortCap::WRITE | PortCap::SUBS_WRITE;
+		let output_caps = PortCap::READ | PortCap::SUBS_READ;
+
+		let mut arp_in_ports = Vec::new();
+		let mut arp_out_ports = Vec::new();
+		for i in 0..application.n_arps() {
+			arp_in_ports.push(create_port(
+				&seq,
+				queue,
+				&format!("{}_{}_in", name, i),
+				input_caps
+			)?);
+			arp_out_ports.push(create_port(
+				&seq,
+				queue,
+				&format!("{}_{}_out", name, i),
+				output_caps
+			)?);
+		}
+		let ui_in_port = create_port(&seq, queue, &format!("{}_launchpad_in", name), input_caps)?;
+		let ui_out_port =
+			create_port(&seq, queue, &format!("{}_launchpad_out", name), output_caps)?;
+
+		seq.control_queue(queue, EventType::Start, 0, None)?;
+		seq.drain_output()?;
+
+		Ok(AlsaDriver {
+			buffers: FrameBuffers::new(application.n_arps()),
+			encoder: seq::MidiEvent::new(256)?,
+			decoder: seq::MidiEvent::new(256)?,
+			late_events: Vec::new(),
+			sysex_thru: Vec::new(),
+			seq,
+			queue,
+			ui_in_port,
+			ui_out_port,
+			periods: 0,
+			arp_in_ports,
+			arp_out_ports,
+			application
+		})
+	}
+
+	fn autoconnect(&self) {
+		let own_client = self.seq.client_id().expect("Failed to get ALSA client id");
+		for client in seq::ClientIter::new(&self.seq) {
+			if !client
+				.get_name()
+				.map(|name| name.contains("Launchpad X"))
+				.unwrap_or(false)
+			{
+				continue;
+			}
+			for port in seq::PortIter::new(&self.seq, client.get_client()) {
+				if port
+					.get_name()
+					.map(|name| name.ends_with("MIDI 2"))
+					.unwrap_or(false)
+				{
+					connect(
+						&self.seq,
+						Addr {
+							client: own_client,
+							port: self.ui_out_port
+						},
+						port.addr()
+					)
+					.expect("Failed to connect");
+					connect(
+						&self.seq,
+						port.addr(),
+						Addr {
+							client: own_client,
+							port: self.ui_in_port
+						}
+					)
+					.expect("Failed to connect");
+				}
+			}
+		}
+	}
+
+	/// Current queue time, in frames
+	fn now(&self) -> u64 {
+		let status = self
+			.seq
+			.get_queue_status(self.queue)
+			.expect("Failed to query the queue status");
+		duration_to_frames(status.get_real_time())
+	}
+
+	fn wait_for_period_end(&self) {
+		let period_end = (self.periods + 1) * PERIOD_SIZE as u64;
+		loop {
+			let now = self.now();
+			if now >= period_end {
+				break;
+			}
+			std::thread::sleep(frames_to_duration(period_end - now));
+		}
+	}
+
+	fn process_period(&mut self) {
+		let period_start = self.periods * PERIOD_SIZE as u64;
+
+		self.buffers.clear();
+		self.read_input(period_start);
+
+		let mut frame = self.buffers.frame(PERIOD_SIZE, self.periods == 10);
+		self.application.process(&mut frame);
+
+		self.write_output(period_start + LATENCY_PERIODS * PERIOD_SIZE as u64);
+		self.periods += 1;
+	}
+
+	fn read_input(&mut self, period_start: u64) {
+		let period_end = period_start + PERIOD_SIZE as u64;
+
+		let mut events = std::mem::take(&mut self.late_events);
+		let mut input = self.seq.input();
+		while input.event_input_pending(true).unwrap_or(0) > 0 {
+			let mut event = match input.event_input() {
+				Ok(event) => event,
+				Err(_) => break
+			};
+			let time = event
+				.get_time()
+				.map(duration_to_frames)
+				.unwrap_or(period_start);
+
+			let mut bytes = [0; 256];
+			self.decoder.reset_decode();
+			if let Ok(len) = self.decoder.decode(&mut bytes, &mut event) {
+				events.push((event.get_dest().port, time, bytes[0..len].to_vec()));
+			}
+		}
+
+		for (port, time, bytes) in events {
+			if time >= period_end {
+				self.late_events.push((port, time, bytes));
+				continue;
+			}
+			let time = time.saturating_sub(period_start) as u32;
+
+			if port == self.ui_in_port {
+				use std::convert::TryInto;
+				if let Ok(event) = bytes.as_slice().try_into() {
+					self.buffers
+						.ui_input
+						.push(TimestampedRawMidiEvent { time, event })
+						.ok();
+				}
+			}
+			else if let Some(i) = self.arp_in_ports.iter().position(|p| *p == port) {
+				if is_sysex(&bytes) {
+					self.sysex_thru.push((i, time, bytes));
+				}
+				else if let Some(event) = MidiEvent::parse(&bytes) {
+					self.buffers.arp_inputs[i]
+						.push(TimestampedMidiEvent { time, event })
+						.ok();
+				}
+			}
+		}
+	}
+
+	fn write_output(&mut self, output_start: u64) {
+		for (i, port) in self.arp_out_ports.iter().enumerate() {
+			for event in self.buffers.arp_outputs[i].iter() {
+				send(
+					&self.seq,
+					&mut self.encoder,
+					self.queue,
+					*port,
+					output_start + event.time as u64,
+					&event.event.to_bytes()
+				);
+			}
+		}
+		for (i, time, bytes) in self.sysex_thru.drain(..) {
+			send(
+				&self.seq,
+				&mut self.encoder,
+				self.queue,
+				self.arp_out_ports[i],
+				output_start + time as u64,
+				&bytes
+			);
+		}
+		for (time, bytes) in self.buffers.ui_output.iter() {
+			send(
+				&self.seq,
+				&mut self.encoder,
+				self.queue,
+				self.ui_out_port,
+				output_start + *time as u64,
+				bytes
+			);
+		}
+		self.seq.drain_output().ok();
+	}
+}
+
+fn create_port(seq: &Seq, queue: i32, name: &str, caps: PortCap) -> alsa::Result<i32> {
+	let mut info = PortInfo::empty()?;
+	info.set_name(&CString::new(name).unwrap());
+	info.set_capability(caps);
+	info.set_type(PortType::MIDI_GENERIC | PortType::APPLICATION);
+	// have incoming events time stamped by our queue, so we know where they belong in the period
+	info.set_timestamping(true);
+	info.set_timestamp_real(true);
+	info.set_timestamp_queue(queue);
+	seq.create_port(&info)?;
+	Ok(info.get_port())
+}
+
+fn connect(seq: &Seq, sender: Addr, dest: Addr) -> alsa::Result<()> {
+	let subscription = PortSubscribe::empty()?;
+	subscription.set_sender(sender);
+	subscription.set_dest(dest);
+	seq.subscribe_port(&subscription)
+}
+
+fn send(seq: &Seq, encoder: &mut seq::MidiEvent, queue: i32, port: i32, time: u64, bytes: &[u8]) {
+	encoder.reset_encode();
+	if let Ok((_, Some(mut event))) = encoder.encode(bytes) {
+		event.set_source(port);
+		event.set_subs();
+		event.schedule_real(queue, false, frames_to_duration(time));
+		seq.event_output(&mut event).ok();
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	#[ignore] // needs a running ALSA sequencer
+	fn clock_passes_through_virtual_client() {
+		let (_, save_buffer_receive) = ringbuf::RingBuffer::new(1).split();
+		let (save_buffer_return, _) = ringbuf::RingBuffer::new(1).split();
+		let application = ArpApplication::new(1, save_buffer_receive, save_buffer_return);
+		let mut driver = AlsaDriver::new_with_seq(
+			"arpfisch_test",
+			application,
+			Seq::open(None, None, true).unwrap()
+		)
+		.unwrap();
+		let driver_client = driver.seq.client_id().unwrap();
+
+		let client = Seq::open(None, None, false).unwrap();
+		client
+			.set_client_name(&CString::new("arpfisch_test_client").unwrap())
+			.unwrap();
+		let client_id = client.client_id().unwrap();
+		let out_port = client
+			.create_simple_port(
+				&CString::new("out").unwrap(),
+				PortCap::READ | PortCap::SUBS_READ,
+				PortType::MIDI_GENERIC | PortType::APPLICATION
+			)
+			.unwrap();
+		let in_port = client
+			.create_simple_port(
+				&CString::new("in").unwrap(),
+				PortCap::WRITE | PortCap::SUBS_WRITE,
+				PortType::MIDI_GENERIC | PortType::APPLICATION
+			)
+			.unwrap();
+		connect(
+			&client,
+			Addr {
+				client: client_id,
+				port: out_port
+			},
+			Addr {
+				client: driver_client,
+				port: driver.arp_in_ports[0]
+			}
+		)
+		.unwrap();
+		connect(
+			&client,
+			Addr {
+				client: driver_client,
+				port: driver.arp_out_ports[0]
+			},
+			Addr {
+				client: client_id,
+				port: in_port
+			}
+		)
+		.unwrap();
+
+		let mut encoder = seq::MidiEvent::new(16).unwrap();
+		let (_, event) = encoder.encode(&[0xF8]).unwrap();
+		let mut event = event.unwrap();
+		event.set_source(out_port);
+		event.set_subs();
+		event.set_direct();
+		client.event_output(&mut event).unwrap();
+		client.drain_output().unwrap();
+
+		for _ in 0..(LATENCY_PERIODS + 4) {
+			driver.wait_for_period_end();
+			driver.process_period();
+		}
+
+		let mut input = client.input();
+		let received = input.event_input().unwrap();
+		assert_eq!(received.get_type(), EventType::Clock);
+	}
+}
@@ -0,0 +1,90 @@
+// this file is part of arpfisch. For copyright and licensing details, see main.rs
+
+use crate::driver::{DriverFrame, TimestampedMidiEvent, TimestampedRawMidiEvent};
+use crate::midi::MidiEvent;
+use heapless;
+
+pub type EventBuffer = heapless::Vec<TimestampedMidiEvent, 256>;
+pub type RawEventBuffer = heapless::Vec<TimestampedRawMidiEvent, 256>;
+pub type UiOutputBuffer = heapless::Vec<(u32, heapless::Vec<u8, 32>), 512>;
+
+/// Pre-allocated event buffers for drivers that don't get their MIDI buffers handed
+/// in by the audio system. The driver fills the inputs before calling
+/// [`ArpApplication::process`](crate::application::ArpApplication::process) on a
+/// [`BufferedFrame`] and drains the outputs afterwards, so that no allocation happens
+/// inside the realtime part.
+pub struct FrameBuffers {
+	pub arp_inputs: Vec<EventBuffer>,
+	pub arp_outputs: Vec<EventBuffer>,
+	pub ui_input: RawEventBuffer,
+	pub ui_output: UiOutputBuffer
+}
+
+impl FrameBuffers {
+	pub fn new(n_arps: usize) -> FrameBuffers {
+		FrameBuffers {
+			arp_inputs: (0..n_arps).map(|_| EventBuffer::new()).collect(),
+			arp_outputs: (0..n_arps).map(|_| EventBuffer::new()).collect(),
+			ui_input: RawEventBuffer::new(),
+			ui_output: UiOutputBuffer::new()
+		}
+	}
+
+	pub fn clear(&mut self) {
+		for buffer in self
+			.arp_inputs
+			.iter_mut()
+			.chain(self.arp_outputs.iter_mut())
+		{
+			buffer.clear();
+		}
+		self.ui_input.clear();
+		self.ui_output.clear();
+	}
+
+	pub fn frame(&mut self, len: u32, ui_just_connected: bool) -> BufferedFrame<'_> {
+		BufferedFrame {
+			arp_inputs: &self.arp_inputs,
+			arp_outputs: &mut self.arp_outputs,
+			ui_input: &self.ui_input,
+			ui_output: &mut self.ui_output,
+			len,
+			ui_just_connected
+		}
+	}
+}
+
+pub struct BufferedFrame<'a> {
+	arp_inputs: &'a [EventBuffer],
+	arp_outputs: &'a mut [EventBuffer],
+	ui_input: &'a RawEventBuffer,
+	ui_output: &'a mut UiOutputBuffer,
+	len: u32,
+	ui_just_connected: bool
+}
+
+impl<'a> DriverFrame for BufferedFrame<'a> {
+	type EventIterator = std::iter::Cloned<std::slice::Iter<'a, TimestampedMidiEvent>>;
+	type RawMidiIterator = std::iter::Cloned<std::slice::Iter<'a, TimestampedRawMidiEvent>>;
+
+	fn send_event(&mut self, port_number: usize, time: u32, event: MidiEvent) -> Result<(), ()> {
+		self.arp_outputs[port_number]
+			.push(TimestampedMidiEvent { time, event })
+			.map_err(|_| ())
+	}
+
+	fn read_events(&self, port_number: usize) -> Self::EventIterator {
+		self.arp_inputs[port_number].iter().cloned()
+	}
+
+	fn send_ui_event(&mut self, time: u32, event: &[u8]) -> Result<(), ()> {
+		let bytes = heapless::Vec::from_slice(event)?;
+		self.ui_output.push((time, bytes)).map_err(|_| ())
+	}
+
+	fn read_ui_events(&self) -> Self::RawMidiIterator { self.ui_input.iter().cloned() }
+
+	fn ui_just_connected(&self) -> bool { self.ui_just_connected }
+
+	fn len(&self) -> u32 { self.len }
+}
@@ -1,8 +1,10 @@
 // this file is part of arpfisch. For copyright and licensing details, see main.rs
 
 use crate::application::ArpApplication;
-use crate::driver::{DriverFrame, TimestampedMidiEvent, TimestampedRawMidiEvent};
-use crate::midi::MidiEvent;
+use crate::driver::{
+	DriverFrame, TimestampedMidiEvent, TimestampedRawMidiEvent, TransportPosition
+};
+use crate::midi::{is_sysex, MidiEvent};
 use jack::*;
 
 pub struct JackDriver {
@@ -93,7 +95,28 @@ impl JackDriver {
 			ui_writer: jack::MidiWriter<'a>,
 			ui_input: &'a Port<MidiIn>,
 			scope: &'a ProcessScope,
-			ui_just_connected: bool
+			ui_just_connected: bool,
+			transport: Option<TransportPosition>,
+			/// Number of SysEx messages per arpeggiator input that were passed through already
+			sysex_forwarded: Vec<usize>
+		}
+
+		impl<'a> MyDriverFrame<'a> {
+			/// Passes the SysEx messages up to `until` through to the arpeggiator's output.
+			/// JACK requires the events to be written in order, so this must happen before
+			/// writing any later event.
+			fn forward_sysex(&mut self, port_number: usize, until: u32) {
+				let forwarded = &mut self.sysex_forwarded[port_number];
+				for event in self.arp_inputs[port_number]
+					.iter(self.scope)
+					.filter(|event| is_sysex(event.bytes))
+					.skip(*forwarded)
+					.take_while(|event| event.time <= until)
+				{
+					self.arp_writers[port_number].write(&event).ok();
+					*forwarded += 1;
+				}
+			}
 		}
 
 		struct MyRawMidiIterator<'a>(jack::MidiIter<'a>);
@@ -151,6 +174,7 @@ impl JackDriver {
 				time: u32,
 				event: MidiEvent
 			) -> Result<(), ()> {
+				self.forward_sysex(port_number, time);
 				self.arp_writers[port_number]
 					.write(&jack::RawMidi {
 						time,
@@ -160,6 +184,8 @@ impl JackDriver {
 			}
 
 			fn len(&self) -> u32 { self.scope.n_frames() }
+
+			fn transport(&self) -> Option<TransportPosition> { self.transport }
 		}
 
 		let mut frame = MyDriverFrame {
@@ -172,10 +198,15 @@ impl JackDriver {
 			ui_writer: self.ui_out_port.writer(&scope),
 			ui_input: &self.ui_in_port,
 			scope,
-			ui_just_connected: self.periods == 10
+			ui_just_connected: self.periods == 10,
+			transport: query_transport(client),
+			sysex_forwarded: vec![0; self.arp_in_ports.len()]
 		};
 
 		self.application.process(&mut frame);
+		for i in 0..self.arp_in_ports.len() {
+			frame.forward_sysex(i, u32::MAX);
+		}
 
 		if self.periods == 0 {
 			self.autoconnect(client);
@@ -183,3 +214,30 @@ impl JackDriver {
 		self.periods += 1;
 	}
 }
+
+/// Queries the JACK transport. Returns `None` if no timebase master provides a
+/// bar/beat/tick position.
+fn query_transport(client: &jack::Client) -> Option<TransportPosition> {
+	let mut position = jack_sys::jack_position_t::default();
+	// jack_transport_query is realtime safe, but not wrapped by the jack crate
+	let state = unsafe { jack_sys::jack_transport_query(client.raw(), &mut position) };
+
+	if position.valid & jack_sys::JackPositionBBT == 0 || position.beat_type <= 0.0 {
+		return None;
+	}
+
+	// JACK counts bars and beats starting from 1, and beats in units of `beat_type`
+	let quarters_per_beat = 4.0 / position.beat_type as f64;
+	let beats = ((position.bar - 1) as f64 * position.beats_per_bar as f64
+		+ (position.beat - 1) as f64
+		+ position.tick as f64 / position.ticks_per_beat)
+		* quarters_per_beat;
+	let frames_per_beat =
+		position.frame_rate as f64 * 60.0 / (position.beats_per_minute * quarters_per_beat);
+
+	Some(TransportPosition {
+		rolling: state == jack_sys::JackTransportRolling,
+		beats,
+		frames_per_beat
+	})
+}
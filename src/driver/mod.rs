@@ -3,20 +3,38 @@
 use crate::midi::MidiEvent;
 use heapless;
 
+pub mod alsa;
+pub mod buffered;
 pub mod jack;
+pub mod offline;
+
+/// Drivers without a sample clock of their own emulate one running at this rate.
+pub const SAMPLE_RATE: u64 = 48000;
 
 pub type RawMidiEvent = heapless::Vec<u8, 3>;
 
+#[derive(Clone)]
 pub struct TimestampedMidiEvent {
 	pub time: u32,
 	pub event: MidiEvent
 }
 
+#[derive(Clone)]
 pub struct TimestampedRawMidiEvent {
 	pub time: u32,
 	pub event: RawMidiEvent
 }
 
+/// State of the audio system's transport at the start of a frame
+#[derive(Clone, Copy, Debug)]
+pub struct TransportPosition {
+	pub rolling: bool,
+	/// Position in quarter notes since the start of the song
+	pub beats: f64,
+	/// Current tempo, in frames per quarter note
+	pub frames_per_beat: f64
+}
+
 pub trait DriverFrame {
 	type EventIterator: Iterator<Item = TimestampedMidiEvent>;
 	type RawMidiIterator: Iterator<Item = TimestampedRawMidiEvent>;
@@ -27,4 +45,7 @@ pub trait DriverFrame {
 	fn read_ui_events(&self) -> Self::RawMidiIterator;
 	fn ui_just_connected(&self) -> bool;
 	fn len(&self) -> u32;
+	/// Returns the transport position, if the audio system has a transport with a
+	/// valid bar/beat/tick position.
+	fn transport(&self) -> Option<TransportPosition> { None }
 }
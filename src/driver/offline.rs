@@ -0,0 +1,323 @@
+// this file is part of arpfisch. For copyright and licensing details, see main.rs
+
+use crate::application::ArpApplication;
+use crate::driver::buffered::FrameBuffers;
+use crate::driver::{TimestampedMidiEvent, SAMPLE_RATE};
+use crate::midi::{is_sysex, MidiEvent};
+use midly::live::LiveEvent;
+use midly::{Format, Header, MetaMessage, Smf, Timing, TrackEvent, TrackEventKind};
+use std::path::Path;
+
+const PERIOD_SIZE: u32 = 256;
+const DEFAULT_TEMPO: u32 = 500000; // microseconds per quarter note, i.e. 120 bpm
+
+/// Converts between MIDI ticks and frames, honoring all tempo changes of a MIDI file.
+struct TempoMap {
+	ticks_per_beat: u64,
+	/// `(tick, frame, microseconds per beat)` for every tempo change, sorted by tick
+	changes: Vec<(u64, f64, u32)>
+}
+
+impl TempoMap {
+	fn new(ticks_per_beat: u64, mut tempos: Vec<(u64, u32)>) -> TempoMap {
+		tempos.sort_by_key(|(tick, _)| *tick);
+		let mut changes = vec![(0, 0.0, DEFAULT_TEMPO)];
+		for (tick, tempo) in tempos {
+			let frame =
+				TempoMap::frame_in_segment(ticks_per_beat, *changes.last().unwrap(), tick as f64);
+			if changes.last().unwrap().0 == tick {
+				changes.pop();
+			}
+			changes.push((tick, frame, tempo));
+		}
+		TempoMap {
+			ticks_per_beat,
+			changes
+		}
+	}
+
+	fn frame_in_segment(
+		ticks_per_beat: u64,
+		(tick, frame, tempo): (u64, f64, u32),
+		at: f64
+	) -> f64 {
+		frame
+			+ (at - tick as f64) * tempo as f64 / ticks_per_beat as f64 * SAMPLE_RATE as f64
+				/ 1_000_000.0
+	}
+
+	fn tick_to_frame(&self, tick: f64) -> u64 {
+		let segment = *self
+			.changes
+			.iter()
+			.rev()
+			.find(|change| change.0 as f64 <= tick)
+			.unwrap();
+		TempoMap::frame_in_segment(self.ticks_per_beat, segment, tick).round() as u64
+	}
+
+	fn frame_to_tick(&self, frame: u64) -> u64 {
+		let (tick, segment_frame, tempo) = *self
+			.changes
+			.iter()
+			.rev()
+			.find(|change| change.1 <= frame as f64)
+			.unwrap();
+		let ticks = (frame as f64 - segment_frame) * 1_000_000.0 / SAMPLE_RATE as f64
+			* self.ticks_per_beat as f64
+			/ tempo as f64;
+		tick + ticks.round() as u64
+	}
+}
+
+/// Renders a MIDI file through the arpeggiators without any audio system.
+///
+/// All channel messages of all tracks of `input` are sent to every arpeggiator input,
+/// and MIDI clock is generated from the file's tempo map (and sent to the first
+/// arpeggiator's input, like an external clock would be). The output of each arpeggiator
+/// is written to its own track of `output`, preceded by a tempo track. SysEx messages are
+/// passed through to every arpeggiator's track. At the end, the transport is stopped, so
+/// that no notes are left hanging.
+pub struct OfflineDriver;
+
+impl OfflineDriver {
+	pub fn render(
+		input: &Path,
+		output: &Path,
+		mut application: Box<ArpApplication>
+	) -> anyhow::Result<()> {
+		let data = std::fs::read(input)?;
+		let smf = Smf::parse(&data)?;
+		let ticks_per_beat = match smf.header.timing {
+			Timing::Metrical(ticks_per_beat) => ticks_per_beat.as_int() as u64,
+			Timing::Timecode(..) => {
+				anyhow::bail!("SMPTE timecode based MIDI files are not supported")
+			}
+		};
+
+		let mut tempos = Vec::new();
+		let mut input_events = Vec::new(); // (tick, raw bytes)
+		for track in smf.tracks.iter() {
+			let mut tick = 0;
+			for event in track.iter() {
+				tick += event.delta.as_int() as u64;
+				match event.kind {
+					TrackEventKind::Meta(MetaMessage::Tempo(tempo)) => {
+						tempos.push((tick, tempo.as_int()));
+					}
+					kind => {
+						if let Some(live_event) = kind.as_live_event() {
+							let mut bytes = Vec::new();
+							live_event.write_std(&mut bytes)?;
+							input_events.push((tick, bytes));
+						}
+					}
+				}
+			}
+		}
+		let tempo_map = TempoMap::new(ticks_per_beat, tempos.clone());
+
+		// play one more bar after the last input event to let the arpeggios finish
+		let end_tick = input_events
+			.iter()
+			.map(|(tick, _)| *tick)
+			.max()
+			.unwrap_or(0)
+			+ 4 * ticks_per_beat;
+		let end_frame = tempo_map.tick_to_frame(end_tick as f64);
+
+		let mut frame_events: Vec<(u64, usize, MidiEvent)> = Vec::new(); // (frame, port, event)
+		for (tick, bytes) in input_events.iter() {
+			if let Some(event) = MidiEvent::parse(bytes) {
+				for port in 0..application.n_arps() {
+					frame_events.push((tempo_map.tick_to_frame(*tick as f64), port, event));
+				}
+			}
+		}
+		frame_events.push((0, 0, MidiEvent::Start));
+		for clock in 0.. {
+			let frame = tempo_map.tick_to_frame(clock as f64 * ticks_per_beat as f64 / 24.0);
+			if frame >= end_frame {
+				break;
+			}
+			frame_events.push((frame, 0, MidiEvent::Clock));
+		}
+		frame_events.push((end_frame, 0, MidiEvent::Stop));
+		frame_events.sort_by_key(|(frame, _, _)| *frame);
+
+		let mut output_events: Vec<Vec<(u64, Vec<u8>)>> = vec![Vec::new(); application.n_arps()];
+		for (tick, bytes) in input_events.iter().filter(|(_, bytes)| is_sysex(bytes)) {
+			for events in output_events.iter_mut() {
+				events.push((tempo_map.tick_to_frame(*tick as f64), bytes.clone()));
+			}
+		}
+		let mut buffers = FrameBuffers::new(application.n_arps());
+		let mut next_event = 0;
+		let mut period_start = 0;
+		while period_start <= end_frame {
+			let period_end = period_start + PERIOD_SIZE as u64;
+
+			buffers.clear();
+			while next_event < frame_events.len() && frame_events[next_event].0 < period_end {
+				let (frame, port, event) = frame_events[next_event];
+				buffers.arp_inputs[port]
+					.push(TimestampedMidiEvent {
+						time: (frame - period_start) as u32,
+						event
+					})
+					.map_err(|_| anyhow::anyhow!("Too many input events in a single period"))?;
+				next_event += 1;
+			}
+
+			application.process(&mut buffers.frame(PERIOD_SIZE, false));
+
+			for (port, events) in buffers.arp_outputs.iter().enumerate() {
+				for event in events.iter() {
+					output_events[port].push((
+						period_start + event.time as u64,
+						event.event.to_bytes().to_vec()
+					));
+				}
+			}
+
+			period_start = period_end;
+		}
+
+		let arena = midly::Arena::new();
+		let mut tracks = Vec::new();
+
+		let mut tempo_track = Vec::new();
+		let mut last_tick = 0;
+		tempos.sort_by_key(|(tick, _)| *tick);
+		for (tick, tempo) in tempos {
+			tempo_track.push(TrackEvent {
+				delta: ((tick - last_tick) as u32).into(),
+				kind: TrackEventKind::Meta(MetaMessage::Tempo(tempo.into()))
+			});
+			last_tick = tick;
+		}
+		tempo_track.push(TrackEvent {
+			delta: 0.into(),
+			kind: TrackEventKind::Meta(MetaMessage::EndOfTrack)
+		});
+		tracks.push(tempo_track);
+
+		for mut events in output_events {
+			events.sort_by_key(|(frame, _)| *frame);
+			let mut track = Vec::new();
+			let mut last_tick = 0;
+			for (frame, bytes) in events {
+				let kind = match LiveEvent::parse(&bytes) {
+					Ok(LiveEvent::Realtime(_)) | Err(_) => continue, // clock and transport
+					Ok(live_event) => live_event.as_track_event(&arena)
+				};
+				let tick = tempo_map.frame_to_tick(frame).max(last_tick);
+				track.push(TrackEvent {
+					delta: ((tick - last_tick) as u32).into(),
+					kind
+				});
+				last_tick = tick;
+			}
+			track.push(TrackEvent {
+				delta: 0.into(),
+				kind: TrackEventKind::Meta(MetaMessage::EndOfTrack)
+			});
+			tracks.push(track);
+		}
+
+		let mut result = Smf::new(Header::new(Format::Parallel, smf.header.timing));
+		result.tracks = tracks;
+		result.save(output)?;
+
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::TempoMap;
+
+	#[test]
+	fn tempo_map() {
+		// 120 bpm for one beat, then 60 bpm
+		let tempo_map = TempoMap::new(96, vec![(96, 1000000)]);
+
+		assert_eq!(tempo_map.tick_to_frame(0.0), 0);
+		assert_eq!(tempo_map.tick_to_frame(48.0), 12000);
+		assert_eq!(tempo_map.tick_to_frame(96.0), 24000);
+		assert_eq!(tempo_map.tick_to_frame(192.0), 72000);
+
+		assert_eq!(tempo_map.frame_to_tick(12000), 48);
+		assert_eq!(tempo_map.frame_to_tick(72000), 192);
+	}
+
+	#[test]
+	fn no_hanging_notes() {
+		use super::OfflineDriver;
+		use crate::application::ArpApplication;
+		use crate::arpeggiator::Entry;
+		use midly::{Format, Header, MidiMessage, Smf, Timing, TrackEvent, TrackEventKind};
+
+		let dir = std::env::temp_dir();
+		let input = dir.join(format!("arpfisch-test-{}-in.mid", std::process::id()));
+		let output = dir.join(format!("arpfisch-test-{}-out.mid", std::process::id()));
+
+		// a short key press, which the chord hold keeps playing until the end
+		let note = |delta: u32, vel: u8| TrackEvent {
+			delta: delta.into(),
+			kind: TrackEventKind::Midi {
+				channel: 0.into(),
+				message: MidiMessage::NoteOn {
+					key: 60.into(),
+					vel: vel.into()
+				}
+			}
+		};
+		let mut smf = Smf::new(Header::new(
+			Format::SingleTrack,
+			Timing::Metrical(96.into())
+		));
+		smf.tracks.push(vec![note(0, 100), note(24, 0)]);
+		smf.save(&input).unwrap();
+
+		let (_, save_buffer_receive) = ringbuf::RingBuffer::new(1).split();
+		let (save_buffer_return, _) = ringbuf::RingBuffer::new(1).split();
+		let mut app = ArpApplication::new(1, save_buffer_receive, save_buffer_return);
+		let instance = app.arp_instance_mut(0);
+		instance.arp.chord_hold = true;
+		for step in 0..8 {
+			// notes longer than the rest of the song
+			instance.patterns[0]
+				.set(
+					step,
+					Entry {
+						len_steps: 16,
+						..Entry::default()
+					}
+				)
+				.unwrap();
+		}
+
+		OfflineDriver::render(&input, &output, app).unwrap();
+		let data = std::fs::read(&output).unwrap();
+		std::fs::remove_file(&input).ok();
+		std::fs::remove_file(&output).ok();
+
+		let (mut note_ons, mut note_offs) = (0, 0);
+		for event in Smf::parse(&data).unwrap().tracks.iter().flatten() {
+			match event.kind {
+				TrackEventKind::Midi {
+					message: MidiMessage::NoteOn { vel, .. },
+					..
+				} if vel > 0 => note_ons += 1,
+				TrackEventKind::Midi {
+					message: MidiMessage::NoteOn { .. } | MidiMessage::NoteOff { .. },
+					..
+				} => note_offs += 1,
+				_ => ()
+			}
+		}
+		assert!(note_ons > 0);
+		assert_eq!(note_ons, note_offs);
+	}
+}
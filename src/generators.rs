@@ -0,0 +1,239 @@
+// this file is part of arpfisch. For copyright and licensing details, see main.rs
+
+use crate::arpeggiator::{ArpeggioData, Entry};
+use crate::random::Random;
+
+/// Whether step `i` of the euclidean rhythm with `pulses` evenly distributed over `steps`
+/// is a hit. The rhythm is rotated to the right by `rotation` steps.
+pub fn euclidean(pulses: usize, steps: usize, rotation: usize, i: usize) -> bool {
+	if steps == 0 {
+		return false;
+	}
+	let i = (i % steps + steps - rotation % steps) % steps;
+	(i * pulses) % steps < pulses
+}
+
+/// Replaces the `row` of the pattern by a euclidean rhythm, made of copies of `template`.
+/// If the rhythm is shorter than the pattern, the row loops with the rhythm's length.
+/// If it is longer, the pattern is extended by empty steps.
+pub fn fill_euclidean(
+	pattern: &mut ArpeggioData,
+	row: isize,
+	pulses: usize,
+	steps: usize,
+	rotation: usize,
+	template: &Entry
+) {
+	if steps > pattern.pattern.len() {
+		pattern.pattern.resize_default(steps).ok();
+	}
+	for pos in 0..pattern.pattern.len() {
+		pattern.delete_all(pos, row);
+	}
+	pattern.set_row_len(
+		row,
+		if steps < pattern.pattern.len() {
+			Some(steps)
+		}
+		else {
+			None
+		}
+	);
+	for pos in 0..steps {
+		if euclidean(pulses, steps, rotation, pos) {
+			pattern
+				.set(
+					pos,
+					Entry {
+						note: row,
+						..template.clone()
+					}
+				)
+				.ok();
+		}
+	}
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum ArpMode {
+	Up,
+	Down,
+	UpDown,
+	DownUp,
+	/// Alternating between the lowest and highest remaining notes
+	Converge,
+	/// From the middle outwards
+	Diverge,
+	/// Upwards through the chord order, which is not necessarily sorted by pitch
+	AsPlayed,
+	/// Every note once per pass, in random order
+	Random
+}
+
+pub const ARP_MODES: [ArpMode; 8] = [
+	ArpMode::Up,
+	ArpMode::Down,
+	ArpMode::UpDown,
+	ArpMode::DownUp,
+	ArpMode::Converge,
+	ArpMode::Diverge,
+	ArpMode::AsPlayed,
+	ArpMode::Random
+];
+
+/// Returns the chord note index and the octave for each step of a classic arpeggio
+/// over `n_notes` chord notes and `n_octaves` octaves.
+pub fn arp_sequence(
+	mode: ArpMode,
+	n_notes: usize,
+	n_octaves: usize,
+	random: &mut Random
+) -> heapless::Vec<(isize, i32), 64> {
+	let up: heapless::Vec<(isize, i32), 64> = (0..n_octaves)
+		.flat_map(|octave| (0..n_notes).map(move |note| (note as isize, octave as i32)))
+		.take(64)
+		.collect();
+	let len = up.len();
+	let down: heapless::Vec<(isize, i32), 64> = up.iter().rev().copied().collect();
+	// skips the turning points, which would otherwise be played twice
+	let inner = |sequence: &heapless::Vec<(isize, i32), 64>| {
+		sequence
+			.iter()
+			.copied()
+			.skip(1)
+			.take(len.saturating_sub(2))
+			.collect::<heapless::Vec<_, 64>>()
+	};
+
+	match mode {
+		ArpMode::Up | ArpMode::AsPlayed => up,
+		ArpMode::UpDown => up.iter().copied().chain(inner(&down)).take(64).collect(),
+		ArpMode::DownUp => down.iter().copied().chain(inner(&up)).take(64).collect(),
+		ArpMode::Down => down,
+		ArpMode::Converge => (0..len)
+			.map(|i| {
+				if i % 2 == 0 {
+					up[i / 2]
+				}
+				else {
+					up[len - 1 - i / 2]
+				}
+			})
+			.collect(),
+		ArpMode::Diverge => (0..len)
+			.rev()
+			.map(|i| {
+				if i % 2 == 0 {
+					up[i / 2]
+				}
+				else {
+					up[len - 1 - i / 2]
+				}
+			})
+			.collect(),
+		ArpMode::Random => {
+			let mut sequence = up;
+			for i in (1..len).rev() {
+				sequence.swap(i, random.next_u32() as usize % (i + 1));
+			}
+			sequence
+		}
+	}
+}
+
+/// Replaces the whole pattern by a classic arpeggio made of copies of `template`. The
+/// pattern length is set to the arpeggio's length.
+pub fn fill_arp_mode(
+	pattern: &mut ArpeggioData,
+	mode: ArpMode,
+	n_notes: usize,
+	n_octaves: usize,
+	template: &Entry,
+	random: &mut Random
+) {
+	let sequence = arp_sequence(mode, n_notes, n_octaves, random);
+	pattern.pattern.clear();
+	pattern.row_lengths.clear();
+	for (note, octave) in sequence {
+		let mut step = heapless::Vec::new();
+		step.push(Entry {
+			note,
+			transpose: template.transpose + 12 * octave,
+			..template.clone()
+		})
+		.ok();
+		pattern.pattern.push(step).ok();
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn rhythm(pulses: usize, steps: usize, rotation: usize) -> String {
+		(0..steps)
+			.map(|i| {
+				if euclidean(pulses, steps, rotation, i) {
+					'x'
+				}
+				else {
+					'.'
+				}
+			})
+			.collect()
+	}
+
+	#[test]
+	fn euclidean_rhythms() {
+		assert_eq!(rhythm(3, 8, 0), "x..x..x.");
+		assert_eq!(rhythm(3, 8, 1), ".x..x..x");
+		assert_eq!(rhythm(5, 8, 0), "x.x.xx.x");
+		assert_eq!(rhythm(4, 4, 2), "xxxx");
+		assert_eq!(rhythm(0, 5, 0), ".....");
+	}
+
+	#[test]
+	fn fill_euclidean_lengths() {
+		use crate::arpeggiator::ArpeggiatorInstance;
+
+		let mut pattern = ArpeggiatorInstance::new().patterns[0].clone();
+		let hits = |pattern: &ArpeggioData| {
+			(0..pattern.pattern.len())
+				.filter(|pos| pattern.filter(*pos, 2).count() > 0)
+				.collect::<Vec<_>>()
+		};
+
+		fill_euclidean(&mut pattern, 2, 3, 4, 0, &Entry::default());
+		assert_eq!(pattern.pattern.len(), 8);
+		assert_eq!(pattern.own_row_len(2), Some(4));
+		assert_eq!(hits(&pattern), [0, 2, 3]);
+
+		// a longer rhythm is not cut off
+		fill_euclidean(&mut pattern, 2, 4, 16, 0, &Entry::default());
+		assert_eq!(pattern.pattern.len(), 16);
+		assert_eq!(pattern.own_row_len(2), None);
+		assert_eq!(hits(&pattern), [0, 4, 8, 12]);
+	}
+
+	#[test]
+	fn arp_modes() {
+		let mut random = Random::new(1);
+		let mut notes = |mode| {
+			arp_sequence(mode, 3, 2, &mut random)
+				.iter()
+				.map(|(note, octave)| note + 3 * *octave as isize)
+				.collect::<Vec<_>>()
+		};
+
+		assert_eq!(notes(ArpMode::Up), [0, 1, 2, 3, 4, 5]);
+		assert_eq!(notes(ArpMode::Down), [5, 4, 3, 2, 1, 0]);
+		assert_eq!(notes(ArpMode::UpDown), [0, 1, 2, 3, 4, 5, 4, 3, 2, 1]);
+		assert_eq!(notes(ArpMode::DownUp), [5, 4, 3, 2, 1, 0, 1, 2, 3, 4]);
+		assert_eq!(notes(ArpMode::Converge), [0, 5, 1, 4, 2, 3]);
+		assert_eq!(notes(ArpMode::Diverge), [3, 2, 4, 1, 5, 0]);
+
+		let mut random_notes = notes(ArpMode::Random);
+		random_notes.sort();
+		assert_eq!(random_notes, [0, 1, 2, 3, 4, 5]);
+	}
+}
@@ -0,0 +1,107 @@
+// this file is part of arpfisch. For copyright and licensing details, see main.rs
+
+use crate::arpeggiator::{ArpeggioData, ChordOrder, Entry};
+use crate::generators::{fill_arp_mode, ArpMode, ARP_MODES};
+use crate::grid_controllers::{Color, GridButtonEvent, LightingMode};
+use crate::random::Random;
+
+/// Replaces the pattern by a classic arpeggio, as a starting point for editing
+pub struct ArpPresetScreen {
+	mode: Option<ArpMode>,
+	n_notes: usize,
+	n_octaves: usize
+}
+
+const MODE_Y: u8 = 7;
+const NOTES_Y: u8 = 5;
+const OCTAVES_Y: u8 = 3;
+const MAX_OCTAVES: usize = 3;
+
+impl ArpPresetScreen {
+	pub fn new() -> ArpPresetScreen {
+		ArpPresetScreen {
+			mode: None,
+			n_notes: 3,
+			n_octaves: 1
+		}
+	}
+
+	pub fn handle_input(
+		&mut self,
+		event: GridButtonEvent,
+		pattern: &mut ArpeggioData,
+		chord_order: &mut ChordOrder,
+		octave: i32,
+		intensity: f32,
+		time: u64
+	) {
+		use GridButtonEvent::*;
+
+		match event {
+			Down(x, MODE_Y, _) if x < 8 => {
+				self.mode = Some(ARP_MODES[x as usize]);
+			}
+			Down(x, NOTES_Y, _) if x < 8 => {
+				self.n_notes = x as usize + 1;
+			}
+			Down(x, OCTAVES_Y, _) if (x as usize) < MAX_OCTAVES => {
+				self.n_octaves = x as usize + 1;
+			}
+			_ => return
+		}
+
+		if let Some(mode) = self.mode {
+			// as played relies on the order of arrival, the other modes on the pitch order
+			if mode == ArpMode::AsPlayed {
+				*chord_order = ChordOrder::Arrival;
+			}
+			else if *chord_order == ChordOrder::Arrival {
+				*chord_order = ChordOrder::Ascending;
+			}
+
+			// the edit screen can only show octaves up to +2
+			let n_octaves = self.n_octaves.min((3 - octave) as usize);
+			fill_arp_mode(
+				pattern,
+				mode,
+				self.n_notes,
+				n_octaves,
+				&Entry {
+					intensity,
+					transpose: 12 * octave,
+					..Entry::default()
+				},
+				&mut Random::new(time as u32)
+			);
+		}
+	}
+
+	pub fn draw(&mut self, array: &mut [[Option<LightingMode>; 9]; 8]) {
+		use LightingMode::*;
+
+		for (x, mode) in ARP_MODES.iter().enumerate() {
+			array[x][MODE_Y as usize] = Some(Solid(if self.mode == Some(*mode) {
+				Color::White(1.0)
+			}
+			else {
+				Color::Color(x as u16 * 45, 0.5)
+			}));
+		}
+		for (x, column) in array.iter_mut().enumerate() {
+			column[NOTES_Y as usize] = Some(Solid(if x + 1 == self.n_notes {
+				Color::White(1.0)
+			}
+			else {
+				Color::Color(120, if x < self.n_notes { 0.5 } else { 0.1 })
+			}));
+		}
+		for (x, column) in array.iter_mut().enumerate().take(MAX_OCTAVES) {
+			column[OCTAVES_Y as usize] = Some(Solid(if x + 1 == self.n_octaves {
+				Color::White(1.0)
+			}
+			else {
+				Color::Color(240, if x < self.n_octaves { 0.5 } else { 0.1 })
+			}));
+		}
+	}
+}
@@ -0,0 +1,125 @@
+// this file is part of arpfisch. For copyright and licensing details, see main.rs
+
+use crate::arpeggiator::{Chain, ChainEntry};
+use crate::grid_controllers::{Color, GridButtonEvent, LightingMode};
+
+/// Edits the chain of patterns of the current arpeggiator. The upper two rows show the
+/// chain entries, the rows below set the pattern and the repeats of the selected entry.
+pub struct ChainScreen {
+	selected: Option<usize>
+}
+
+const ENTRIES_Y: u8 = 7;
+const PATTERN_Y: u8 = 4;
+const REPEATS_Y: u8 = 2;
+const BUTTONS_Y: u8 = 0;
+const DELETE_X: u8 = 0;
+const ONE_SHOT_X: u8 = 7;
+
+fn pattern_hue(pattern: usize) -> u16 { (pattern * 45) as u16 }
+
+impl ChainScreen {
+	pub fn new() -> ChainScreen { ChainScreen { selected: None } }
+
+	pub fn handle_input(
+		&mut self,
+		event: GridButtonEvent,
+		chain: &mut Chain,
+		active_pattern: usize
+	) {
+		use GridButtonEvent::*;
+
+		let selected = self.selected.filter(|i| *i < chain.entries.len());
+		match event {
+			Down(x, y, _) if x < 8 && (y == ENTRIES_Y || y + 1 == ENTRIES_Y) => {
+				let i = x as usize + 8 * (ENTRIES_Y - y) as usize;
+				if i == chain.entries.len() {
+					let entry = ChainEntry {
+						pattern: active_pattern,
+						repeats: 1
+					};
+					if chain.entries.push(entry).is_ok() {
+						self.selected = Some(i);
+					}
+				}
+				else if i < chain.entries.len() {
+					self.selected = Some(i);
+				}
+			}
+			Down(x, PATTERN_Y, _) if x < 8 => {
+				if let Some(i) = selected {
+					chain.entries[i].pattern = x as usize;
+				}
+			}
+			Down(x, REPEATS_Y, _) if x < 8 => {
+				if let Some(i) = selected {
+					chain.entries[i].repeats = x as usize + 1;
+				}
+			}
+			Down(DELETE_X, BUTTONS_Y, _) => {
+				if let Some(i) = selected {
+					chain.entries.remove(i);
+					self.selected = i.checked_sub(1);
+				}
+			}
+			Down(ONE_SHOT_X, BUTTONS_Y, _) => {
+				chain.one_shot = !chain.one_shot;
+			}
+			_ => ()
+		}
+	}
+
+	pub fn draw(&mut self, array: &mut [[Option<LightingMode>; 9]; 8], chain: &Chain) {
+		use LightingMode::*;
+
+		let selected = self.selected.filter(|i| *i < chain.entries.len());
+
+		// the entries, with the one that is currently playing in white
+		for i in 0..16 {
+			let (x, y) = (i % 8, ENTRIES_Y as usize - i / 8);
+			array[x][y] = Some(if let Some(entry) = chain.entries.get(i) {
+				let color = if chain.position() == Some(i) {
+					Color::White(1.0)
+				}
+				else {
+					Color::Color(pattern_hue(entry.pattern), 0.7)
+				};
+				if selected == Some(i) {
+					Fade(color)
+				}
+				else {
+					Solid(color)
+				}
+			}
+			else if i == chain.entries.len() {
+				Solid(Color::White(0.1))
+			}
+			else {
+				Off
+			});
+		}
+
+		if let Some(entry) = selected.map(|i| chain.entries[i]) {
+			for (x, column) in array.iter_mut().enumerate() {
+				column[PATTERN_Y as usize] = Some(Solid(if x == entry.pattern {
+					Color::White(1.0)
+				}
+				else {
+					Color::Color(pattern_hue(x), 0.2)
+				}));
+				column[REPEATS_Y as usize] = Some(Solid(if x + 1 == entry.repeats {
+					Color::White(1.0)
+				}
+				else {
+					Color::Color(60, if x < entry.repeats { 0.5 } else { 0.1 })
+				}));
+			}
+			array[DELETE_X as usize][BUTTONS_Y as usize] = Some(Solid(Color::Color(0, 0.7)));
+		}
+
+		array[ONE_SHOT_X as usize][BUTTONS_Y as usize] = Some(Solid(Color::Color(
+			270,
+			if chain.one_shot { 1.0 } else { 0.2 }
+		)));
+	}
+}
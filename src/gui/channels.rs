@@ -0,0 +1,89 @@
+// this file is part of arpfisch. For copyright and licensing details, see main.rs
+
+use crate::grid_controllers::{Color, GridButtonEvent, LightingMode};
+use crate::midi::Channel;
+
+pub struct ChannelScreen {}
+
+const IN_CHANNEL_Y: u8 = 7;
+const OMNI_Y: u8 = 5;
+const OUT_CHANNEL_Y: u8 = 3;
+
+/// Maps a button to a channel, if it lies within the two rows starting at `top_y`.
+fn channel_at(x: u8, y: u8, top_y: u8) -> Option<Channel> {
+	if x < 8 && y <= top_y && y + 1 >= top_y {
+		Some(Channel(x + 8 * (top_y - y)))
+	}
+	else {
+		None
+	}
+}
+
+impl ChannelScreen {
+	pub fn new() -> ChannelScreen { ChannelScreen {} }
+
+	pub fn handle_input(
+		&mut self,
+		event: GridButtonEvent,
+		in_channel: &mut Channel,
+		omni: &mut bool,
+		out_channel: &mut Channel
+	) {
+		use GridButtonEvent::*;
+
+		match event {
+			Down(0, OMNI_Y, _) => {
+				*omni = !*omni;
+			}
+			Down(x, y, _) => {
+				if let Some(channel) = channel_at(x, y, IN_CHANNEL_Y) {
+					*in_channel = channel;
+					*omni = false;
+				}
+				if let Some(channel) = channel_at(x, y, OUT_CHANNEL_Y) {
+					*out_channel = channel;
+				}
+			}
+			_ => ()
+		}
+	}
+
+	pub fn draw(
+		&mut self,
+		array: &mut [[Option<LightingMode>; 9]; 8],
+		in_channel: Channel,
+		omni: bool,
+		out_channel: Channel
+	) {
+		use LightingMode::*;
+
+		for x in 0..8 {
+			for y in 0..8 {
+				if let Some(channel) = channel_at(x, y, IN_CHANNEL_Y) {
+					array[x as usize][y as usize] =
+						Some(Solid(if channel == in_channel && !omni {
+							Color::White(1.0)
+						}
+						else {
+							Color::Color(150, 0.3)
+						}));
+				}
+				if let Some(channel) = channel_at(x, y, OUT_CHANNEL_Y) {
+					array[x as usize][y as usize] = Some(Solid(if channel == out_channel {
+						Color::White(1.0)
+					}
+					else {
+						Color::Color(30, 0.3)
+					}));
+				}
+			}
+		}
+
+		array[0][OMNI_Y as usize] = Some(Solid(if omni {
+			Color::Color(150, 1.0)
+		}
+		else {
+			Color::Color(150, 0.1)
+		}));
+	}
+}
@@ -10,7 +10,7 @@ fn log2(value: u32) -> u32 {
 	// FIXME use u32::log2 once it's stable
 	assert!(value != 0);
 	for exponent in 0..32 {
-		if value % 2u32.pow(exponent + 1) != 0 {
+		if !value.is_multiple_of(2u32.pow(exponent + 1)) {
 			return exponent;
 		}
 	}
@@ -67,22 +67,22 @@ impl ClockDivisionScreen {
 		let log2 = log2(ticks_per_step);
 		let uneven = ticks_per_step / 2u32.pow(log2);
 
-		let blinking = if step % 2 == 0 {
+		let blinking = if step.is_multiple_of(2) {
 			Solid(Color::White(1.0))
 		}
 		else {
 			Solid(Color::White(0.3))
 		};
 
-		for x in 0..8 {
-			array[x][UNEVEN_Y as usize] = if (2 * x + 1) as u32 == uneven {
+		for (x, column) in array.iter_mut().enumerate() {
+			column[UNEVEN_Y as usize] = if (2 * x + 1) as u32 == uneven {
 				Some(blinking)
 			}
 			else {
 				Some(Solid(Color::Color(60, 0.7)))
 			};
 
-			array[x][POWER2_Y as usize] = if x as u32 == log2 {
+			column[POWER2_Y as usize] = if x as u32 == log2 {
 				Some(blinking)
 			}
 			else {
@@ -1,75 +1,125 @@
 // this file is part of arpfisch. For copyright and licensing details, see main.rs
 
-use crate::arpeggiator::{ArpeggioData, ClockMode, RepeatMode};
+use crate::arpeggiator::{ChordOrder, ClockMode, RepeatMode, VelocitySource};
 use crate::grid_controllers::{Color, GridButtonEvent, LightingMode};
+
+use super::{DrawContext, InputContext};
 use crate::tempo_detector::TempoDetector;
 
 pub struct ConfigScreen {
 	tempo: TempoDetector,
-	restart_transport_hit_time: u64
+	restart_transport_hit_time: u64,
+	stop_transport_hit_time: u64,
+	/// Whether the upper half sets the length of the selected row instead of the pattern
+	row_length_mode: bool
 }
 
 impl ConfigScreen {
 	pub fn new() -> ConfigScreen {
 		ConfigScreen {
 			tempo: TempoDetector::new(),
-			restart_transport_hit_time: 0
+			restart_transport_hit_time: 0,
+			stop_transport_hit_time: 0,
+			row_length_mode: false
 		}
 	}
 
 	pub fn handle_input(
 		&mut self,
 		event: GridButtonEvent,
-		pattern: &mut ArpeggioData,
+		ctx: &mut InputContext,
 		pane_height: &mut usize,
-		restart_transport_pending: &mut bool,
-		use_external_clock: bool,
-		clock_mode: &mut ClockMode,
-		time_between_midiclocks: &mut u64,
-		time: u64
+		selected_row: isize
 	) {
 		use GridButtonEvent::*;
 
 		match event {
 			Down(2, 0, _) => {
-				pattern.repeat_mode = RepeatMode::Clamp;
+				ctx.pattern.repeat_mode = RepeatMode::Clamp;
 			}
 			Down(2, 1, _) => {
-				pattern.repeat_mode = RepeatMode::Mirror;
+				ctx.pattern.repeat_mode = RepeatMode::Mirror;
 			}
 			Down(2, 2, _) => {
-				pattern.repeat_mode = RepeatMode::Repeat(12);
+				ctx.pattern.repeat_mode = RepeatMode::Repeat(12);
+			}
+			Down(2, 3, _) => {
+				ctx.pattern.repeat_mode = RepeatMode::Skip;
+			}
+			Down(1, 0, _) => {
+				ctx.pattern.repeat_mode = RepeatMode::Random;
+			}
+			Down(4, 0, _) => {
+				*ctx.velocity_source = VelocitySource::Step;
+			}
+			Down(4, 1, _) => {
+				*ctx.velocity_source = VelocitySource::Blend;
+			}
+			Down(4, 2, _) => {
+				*ctx.velocity_source = VelocitySource::Input;
+			}
+			Down(5, 0, _) => {
+				*ctx.chord_order = ChordOrder::Ascending;
+			}
+			Down(5, 1, _) => {
+				*ctx.chord_order = ChordOrder::Descending;
+			}
+			Down(5, 2, _) => {
+				*ctx.chord_order = ChordOrder::Arrival;
 			}
 			Down(6, 1, _) => {
-				*restart_transport_pending = true;
-				self.restart_transport_hit_time = time;
+				*ctx.restart_transport_pending = true;
+				self.restart_transport_hit_time = ctx.time;
+			}
+			Down(6, 0, _) => {
+				*ctx.stop_transport_pending = true;
+				self.stop_transport_hit_time = ctx.time;
+			}
+			Down(6, 2, _) => {
+				*ctx.continue_transport_pending = true;
+			}
+			Down(6, 3, _) => {
+				*ctx.fill = !*ctx.fill;
 			}
 			Down(7, 2, _) => {
-				if !use_external_clock {
-					self.tempo.beat(time);
+				if !ctx.use_external_clock {
+					self.tempo.beat(ctx.time);
 					if self.tempo.time_per_beat() <= 48000 * 2 && self.tempo.time_per_beat() >= 10 {
-						*time_between_midiclocks = self.tempo.time_per_beat() as u64 / 24;
+						*ctx.time_between_midiclocks = self.tempo.time_per_beat() as u64 / 24;
 					}
 				}
 			}
 			Down(7, 1, _) => {
 				use ClockMode::*;
-				*clock_mode = match *clock_mode {
+				*ctx.clock_mode = match *ctx.clock_mode {
 					Internal => Auto,
 					Auto => External,
-					External => Internal
+					External => JackTransport,
+					JackTransport => Internal
 				};
 			}
+			Down(1, 3, _) => {
+				self.row_length_mode = !self.row_length_mode;
+			}
+			Down(1, 2, _) => {
+				ctx.pattern.set_row_len(selected_row, None);
+			}
 			Down(x, y, _) if (4..8).contains(&y) && x < 8 => {
 				let new_len = x + 8 * (8 - y - 1) + 1;
-				pattern.pattern.resize_default(new_len as usize).ok();
+				if self.row_length_mode {
+					ctx.pattern
+						.set_row_len(selected_row, Some(new_len as usize));
+				}
+				else {
+					ctx.pattern.pattern.resize_default(new_len as usize).ok();
+				}
 			}
 			Down(0, y, _) if y < 4 => {
 				*pane_height = 8 / (y + 1) as usize;
 			}
-			Down(3, y, _) if y < 4 => match pattern.repeat_mode {
+			Down(3, y, _) if y < 4 => match ctx.pattern.repeat_mode {
 				RepeatMode::Repeat(_) => {
-					pattern.repeat_mode = RepeatMode::Repeat((y as i32 - 1) * 12);
+					ctx.pattern.repeat_mode = RepeatMode::Repeat((y as i32 - 1) * 12);
 				}
 				_ => {}
 			},
@@ -80,15 +130,25 @@ impl ConfigScreen {
 	pub fn draw(
 		&mut self,
 		array: &mut [[Option<LightingMode>; 9]; 8],
-		pattern: &ArpeggioData,
+		ctx: &DrawContext,
 		pane_height: usize,
-		use_external_clock: bool,
-		external_clock_present: bool,
-		clock_mode: ClockMode,
-		time: u64
+		selected_row: isize
 	) {
 		use LightingMode::*;
 
+		let &DrawContext {
+			pattern,
+			use_external_clock,
+			external_clock_present,
+			clock_mode,
+			playing,
+			velocity_source,
+			chord_order,
+			fill,
+			time,
+			..
+		} = ctx;
+
 		array[7][2] = Some(match (use_external_clock, external_clock_present) {
 			(true, true) => Alternate(Color::Color(150, 0.7), Color::White(1.0)),
 			(true, false) => Solid(Color::Color(175, 0.0)),
@@ -97,18 +157,25 @@ impl ConfigScreen {
 		array[7][1] = Some(Solid(match clock_mode {
 			ClockMode::Internal => Color::Color(30, 0.7),
 			ClockMode::External => Color::Color(150, 0.7),
-			ClockMode::Auto => Color::White(0.7)
+			ClockMode::Auto => Color::White(0.7),
+			ClockMode::JackTransport => Color::Color(270, 0.7)
 		}));
 
-		// display the pattern length
+		// display the pattern length, or the length of the selected row
 		let pattern_len = pattern.pattern.len();
+		let (len, hue) = if self.row_length_mode {
+			(pattern.row_len(selected_row), 180)
+		}
+		else {
+			(pattern_len, 0)
+		};
 		for y in 4..8 {
 			for x in 0..8 {
 				let curr_pos = x + (8 - y - 1) * 8 + 1;
-				array[x][y] = if curr_pos < pattern_len {
-					Some(Solid(Color::Color(0, 0.7)))
+				array[x][y] = if curr_pos < len {
+					Some(Solid(Color::Color(hue, 0.7)))
 				}
-				else if curr_pos == pattern_len {
+				else if curr_pos == len {
 					Some(Solid(Color::White(1.0)))
 				}
 				else {
@@ -117,6 +184,15 @@ impl ConfigScreen {
 			}
 		}
 
+		// row length mode, and the button to make the row follow the pattern length again
+		array[1][3] = Some(Solid(Color::Color(
+			180,
+			if self.row_length_mode { 1.0 } else { 0.2 }
+		)));
+		if pattern.own_row_len(selected_row).is_some() {
+			array[1][2] = Some(Solid(Color::Color(0, 0.7)));
+		}
+
 		// display the number of panes
 		let n_panes = 8 / pane_height;
 		for i in 0..4 {
@@ -129,9 +205,10 @@ impl ConfigScreen {
 		}
 
 		// repeat mode
-		for i in 0..3 {
+		for i in 0..4 {
 			array[2][i] = Some(Solid(Color::White(0.3)));
 		}
+		array[1][0] = Some(Solid(Color::White(0.3)));
 		match pattern.repeat_mode {
 			RepeatMode::Clamp => {
 				array[2][0] = Some(Solid(Color::Color(60, 1.0)));
@@ -139,6 +216,12 @@ impl ConfigScreen {
 			RepeatMode::Mirror => {
 				array[2][1] = Some(Solid(Color::Color(180, 1.0)));
 			}
+			RepeatMode::Skip => {
+				array[2][3] = Some(Solid(Color::Color(0, 1.0)));
+			}
+			RepeatMode::Random => {
+				array[1][0] = Some(Solid(Color::Color(120, 1.0)));
+			}
 			RepeatMode::Repeat(transpose) => {
 				array[2][2] = Some(Solid(Color::Color(300, 1.0)));
 				for i in 0..4 {
@@ -152,6 +235,38 @@ impl ConfigScreen {
 			}
 		}
 
+		// velocity source
+		for i in 0..3 {
+			array[4][i] = Some(Solid(Color::White(0.3)));
+		}
+		match velocity_source {
+			VelocitySource::Step => {
+				array[4][0] = Some(Solid(Color::Color(0, 1.0)));
+			}
+			VelocitySource::Blend => {
+				array[4][1] = Some(Solid(Color::Color(45, 1.0)));
+			}
+			VelocitySource::Input => {
+				array[4][2] = Some(Solid(Color::Color(90, 1.0)));
+			}
+		}
+
+		// chord order
+		for i in 0..3 {
+			array[5][i] = Some(Solid(Color::White(0.3)));
+		}
+		match chord_order {
+			ChordOrder::Ascending => {
+				array[5][0] = Some(Solid(Color::Color(240, 1.0)));
+			}
+			ChordOrder::Descending => {
+				array[5][1] = Some(Solid(Color::Color(270, 1.0)));
+			}
+			ChordOrder::Arrival => {
+				array[5][2] = Some(Solid(Color::Color(300, 1.0)));
+			}
+		}
+
 		// restart transport button
 		array[6][1] = Some(Solid(
 			if time < self.restart_transport_hit_time + 48000 / 2 {
@@ -161,5 +276,29 @@ impl ConfigScreen {
 				Color::Color(0, 0.7)
 			}
 		));
+
+		// stop and play buttons
+		array[6][0] = Some(Solid(
+			if !playing || time < self.stop_transport_hit_time + 48000 / 2 {
+				Color::Color(0, 1.0)
+			}
+			else {
+				Color::Color(0, 0.2)
+			}
+		));
+		array[6][2] = Some(if playing {
+			Solid(Color::Color(120, 1.0))
+		}
+		else {
+			Solid(Color::Color(120, 0.2))
+		});
+
+		// fill mode for conditional steps
+		array[6][3] = Some(if fill {
+			Solid(Color::Color(60, 1.0))
+		}
+		else {
+			Solid(Color::Color(60, 0.2))
+		});
 	}
 }
@@ -3,6 +3,8 @@
 use crate::arpeggiator::{ArpeggioData, Entry};
 use crate::grid_controllers::{Color, GridButtonEvent, LightingMode};
 
+use super::step_edit::{StepEditPage, StepEditScreen};
+
 #[derive(Copy, Clone)]
 struct HeldKey {
 	coords: (u8, u8),
@@ -19,7 +21,12 @@ pub struct EditScreen {
 	last_scroll_update: u64,
 	first_y: isize,
 	currently_held_key: Option<HeldKey>,
-	current_octave: i32
+	pub(super) current_octave: i32,
+	/// Velocity of the last step that was set
+	pub(super) current_intensity: f32,
+	/// The row that was touched last, whose length can be set in the config screen
+	pub(super) selected_row: isize,
+	step_edit: Option<StepEditScreen>
 }
 
 impl EditScreen {
@@ -31,7 +38,10 @@ impl EditScreen {
 			last_scroll_update: 0,
 			first_y: 0,
 			currently_held_key: None,
-			current_octave: 0
+			current_octave: 0,
+			current_intensity: 0.8,
+			selected_row: 0,
+			step_edit: None
 		}
 	}
 
@@ -48,6 +58,7 @@ impl EditScreen {
 		let pane = yy as usize / self.pane_height;
 		let x = xx as isize + self.first_x + 8 * (n_panes - pane - 1) as isize;
 		let y = (yy as isize % self.pane_height as isize) + self.first_y;
+		self.selected_row = y;
 		if x >= 0 && (x as usize) < pattern.pattern.len() {
 			let step_has_any_note = pattern.filter(x as usize, y).count() > 0;
 
@@ -79,6 +90,7 @@ impl EditScreen {
 			if !step_has_any_note {
 				match mode {
 					PressMode::Primary | PressMode::SecondaryUnrelated => {
+						self.current_intensity = velo;
 						pattern
 							.set(
 								x as usize,
@@ -86,7 +98,8 @@ impl EditScreen {
 									note: y,
 									len_steps: 1,
 									intensity: velo,
-									transpose: 12 * self.current_octave
+									transpose: 12 * self.current_octave,
+									..Entry::default()
 								}
 							)
 							.ok();
@@ -133,6 +146,25 @@ impl EditScreen {
 	pub fn handle_input(&mut self, event: GridButtonEvent, pattern: &mut ArpeggioData, time: u64) {
 		use GridButtonEvent::*;
 
+		if let Some(step_edit) = self.step_edit.as_mut() {
+			if !step_edit.handle_input(event, pattern) {
+				self.step_edit = None;
+			}
+			return;
+		}
+
+		// holding a step and pressing a navigation button opens the step's properties. The
+		// remaining pages can only be reached from within, as the octave buttons follow.
+		if let (&Down(x, 8, _), Some(held)) = (&event, self.currently_held_key) {
+			if let Some(page) = StepEditPage::from_button(x).filter(|_| x < 4) {
+				if pattern.filter(held.pos, held.note).count() > 0 {
+					self.currently_held_key = None;
+					self.step_edit = Some(StepEditScreen::new(held.pos, held.note, page));
+					return;
+				}
+			}
+		}
+
 		match event {
 			Down(0, 8, _) => {
 				self.first_y += 1;
@@ -186,10 +218,16 @@ impl EditScreen {
 		array: &mut [[Option<LightingMode>; 9]; 8],
 		pattern: &ArpeggioData,
 		step: f32,
+		total_step: f32,
 		time: u64
 	) {
 		use LightingMode::*;
 
+		if let Some(step_edit) = self.step_edit.as_mut() {
+			step_edit.draw(array, pattern);
+			return;
+		}
+
 		if self.target_first_x != self.first_x && time >= self.last_scroll_update + 1024 {
 			self.first_x += (self.target_first_x - self.first_x).signum();
 			self.last_scroll_update = time;
@@ -197,11 +235,12 @@ impl EditScreen {
 
 		let mut octave_buttons = [Off; 4];
 		if let Some(held) = self.currently_held_key {
+			// other transpositions are only shown in the semitone picker
 			for entry in pattern.filter(held.pos, held.note) {
-				assert!(entry.transpose % 12 == 0);
 				let octave = entry.transpose / 12;
-				assert!((-1..=2).contains(&octave));
-				octave_buttons[(octave + 1) as usize] = Solid(octave_color(octave));
+				if entry.transpose % 12 == 0 && (-1..=2).contains(&octave) {
+					octave_buttons[(octave + 1) as usize] = Solid(octave_color(octave));
+				}
 			}
 		}
 		else {
@@ -220,13 +259,19 @@ impl EditScreen {
 				(8, self.pane_height),
 				(self.first_x + 8 * pane as isize, self.first_y),
 				&pattern,
-				step
+				step,
+				total_step
 			);
 		}
 	}
 }
 
-fn octave_hue(octave: i32) -> u16 { (octave + 1) as u16 * 90 }
+/// Octaves -1 to 2 are red, yellow-green, cyan and purple, with the transpositions in between
+/// in the hues between them. The colours repeat every four octaves.
+pub(super) fn transpose_hue(transpose: i32) -> u16 {
+	((transpose + 12).rem_euclid(48) * 360 / 48) as u16
+}
+fn octave_hue(octave: i32) -> u16 { transpose_hue(12 * octave) }
 fn octave_color(octave: i32) -> Color { Color::Color(octave_hue(octave), 1.0) }
 
 fn draw_into(
@@ -235,7 +280,8 @@ fn draw_into(
 	canvas_size: (usize, usize),
 	pattern_offset: (isize, isize),
 	pattern: &ArpeggioData,
-	step: f32
+	step: f32,
+	total_step: f32
 ) {
 	use LightingMode::*;
 	// draw notes
@@ -252,10 +298,14 @@ fn draw_into(
 							*foo = Some(Solid(Color::White(1.0)));
 						}
 						else {
-							assert!(e.transpose % 12 == 0);
-							let octave = e.transpose / 12;
-							assert!((-1..=2).contains(&octave));
-							let hue = octave_hue(octave) + (30.0 * e.intensity) as u16;
+							// only octaves are shaded by the intensity, to not confuse it with
+							// the transposition
+							let hue = if e.transpose % 12 == 0 {
+								transpose_hue(e.transpose) + (30.0 * e.intensity) as u16
+							}
+							else {
+								transpose_hue(e.transpose)
+							};
 							let color = if i == 0 {
 								Color::Color(hue, 0.25 + 0.75 * e.intensity)
 							}
@@ -270,11 +320,12 @@ fn draw_into(
 		}
 	}
 
-	// draw invalid area
-	for x in 0..canvas_size.0 {
-		let pos = x as isize + pattern_offset.0;
-		if pos < 0 || pos >= pattern.pattern.len() as isize {
-			for y in 0..canvas_size.1 {
+	// draw invalid area, which depends on the row length
+	for y in 0..canvas_size.1 {
+		let row_len = pattern.row_len(y as isize + pattern_offset.1);
+		for x in 0..canvas_size.0 {
+			let pos = x as isize + pattern_offset.0;
+			if pos < 0 || pos >= row_len as isize {
 				array[x + canvas_offset.0][y + canvas_offset.1] = Some(Solid(Color::Color(0, 0.3)));
 			}
 		}
@@ -289,10 +340,15 @@ fn draw_into(
 		}
 	}
 
-	// draw vertical step indicator
-	let hl_x = step as isize - pattern_offset.0;
-	if (0..canvas_size.0 as isize).contains(&hl_x) {
-		for y in 0..canvas_size.1 {
+	// draw vertical step indicator, rows with their own length have their own position
+	for y in 0..canvas_size.1 {
+		let note = y as isize + pattern_offset.1;
+		let row_step = match pattern.own_row_len(note) {
+			Some(_) => total_step.rem_euclid(pattern.row_len(note) as f32),
+			None => step
+		};
+		let hl_x = row_step as isize - pattern_offset.0;
+		if (0..canvas_size.0 as isize).contains(&hl_x) {
 			let foo = &mut array[hl_x as usize + canvas_offset.0][y + canvas_offset.1];
 			*foo = Some(foo.unwrap_or(Off).bright());
 		}
@@ -0,0 +1,124 @@
+// this file is part of arpfisch. For copyright and licensing details, see main.rs
+
+use crate::arpeggiator::{ArpeggioData, Entry};
+use crate::generators::{euclidean, fill_euclidean};
+use crate::grid_controllers::{Color, GridButtonEvent, LightingMode};
+
+/// Generates euclidean rhythms for the row that was touched last in the main view.
+/// Each parameter is set with two rows of buttons, the bottom two rows show the rhythm.
+pub struct EuclidScreen {
+	pulses: usize,
+	steps: usize,
+	rotation: usize
+}
+
+const MAX_STEPS: usize = 16;
+const STEPS_Y: u8 = 7;
+const PULSES_Y: u8 = 5;
+const ROTATION_Y: u8 = 3;
+const PREVIEW_Y: u8 = 1;
+
+/// Maps a button to a value in `0..16`, if it lies within the two rows starting at `top_y`.
+fn value_at(x: u8, y: u8, top_y: u8) -> Option<usize> {
+	if x < 8 && y <= top_y && y + 1 >= top_y {
+		Some(x as usize + 8 * (top_y - y) as usize)
+	}
+	else {
+		None
+	}
+}
+
+impl EuclidScreen {
+	pub fn new() -> EuclidScreen {
+		EuclidScreen {
+			pulses: 4,
+			steps: 16,
+			rotation: 0
+		}
+	}
+
+	pub fn handle_input(
+		&mut self,
+		event: GridButtonEvent,
+		pattern: &mut ArpeggioData,
+		row: isize,
+		octave: i32,
+		intensity: f32
+	) {
+		use GridButtonEvent::*;
+
+		if let Down(x, y, _) = event {
+			if let Some(value) = value_at(x, y, STEPS_Y) {
+				self.steps = value + 1;
+			}
+			else if let Some(value) = value_at(x, y, PULSES_Y) {
+				self.pulses = value + 1;
+			}
+			else if let Some(value) = value_at(x, y, ROTATION_Y) {
+				self.rotation = value;
+			}
+			else {
+				return;
+			}
+			self.pulses = self.pulses.min(self.steps);
+			self.rotation %= self.steps;
+
+			fill_euclidean(
+				pattern,
+				row,
+				self.pulses,
+				self.steps,
+				self.rotation,
+				&Entry {
+					intensity,
+					transpose: 12 * octave,
+					..Entry::default()
+				}
+			);
+		}
+	}
+
+	pub fn draw(&mut self, array: &mut [[Option<LightingMode>; 9]; 8]) {
+		use LightingMode::*;
+
+		for x in 0..8 {
+			for y in 0..8 {
+				let (value, hue, selected) = if let Some(value) = value_at(x, y, STEPS_Y) {
+					(value + 1, 0, self.steps)
+				}
+				else if let Some(value) = value_at(x, y, PULSES_Y) {
+					(value + 1, 60, self.pulses)
+				}
+				else if let Some(value) = value_at(x, y, ROTATION_Y) {
+					(value, 240, self.rotation)
+				}
+				else {
+					continue;
+				};
+
+				array[x as usize][y as usize] = Some(Solid(if value == selected {
+					Color::White(1.0)
+				}
+				else if value < selected {
+					Color::Color(hue, 0.5)
+				}
+				else {
+					Color::Color(hue, 0.1)
+				}));
+			}
+		}
+
+		for i in 0..MAX_STEPS {
+			let (x, y) = (i % 8, PREVIEW_Y as usize - i / 8);
+			array[x][y] = Some(if i >= self.steps {
+				Off
+			}
+			else if euclidean(self.pulses, self.steps, self.rotation, i) {
+				Solid(Color::Color(150, 1.0))
+			}
+			else {
+				Solid(Color::Color(150, 0.1))
+			});
+		}
+	}
+}
@@ -0,0 +1,52 @@
+// this file is part of arpfisch. For copyright and licensing details, see main.rs
+
+use crate::arpeggiator::MixerState;
+use crate::grid_controllers::{Color, GridButtonEvent, LightingMode};
+
+/// Shows one strip per arpeggiator, with buttons for mute, solo and whether the routed
+/// arpeggiators keep receiving notes while muted.
+pub struct MixerScreen {}
+
+const MUTE_Y: u8 = 7;
+const SOLO_Y: u8 = 6;
+const ROUTE_Y: u8 = 5;
+
+impl MixerScreen {
+	pub fn new() -> MixerScreen { MixerScreen {} }
+
+	pub fn handle_input(&mut self, event: GridButtonEvent, mixer: &mut [MixerState]) {
+		use GridButtonEvent::*;
+
+		if let Down(x, y, _) = event {
+			if let Some(state) = mixer.get_mut(x as usize).filter(|_| x < 8) {
+				match y {
+					MUTE_Y => state.muted = !state.muted,
+					SOLO_Y => state.solo = !state.solo,
+					ROUTE_Y => state.route_when_muted = !state.route_when_muted,
+					_ => ()
+				}
+			}
+		}
+	}
+
+	pub fn draw(&mut self, array: &mut [[Option<LightingMode>; 9]; 8], mixer: &[MixerState]) {
+		use LightingMode::*;
+
+		let any_solo = mixer.iter().any(|state| state.solo);
+		for (x, state) in mixer.iter().enumerate().take(8) {
+			// muted ones are red, the others green, dimmed when silenced by another's solo
+			array[x][MUTE_Y as usize] = Some(Solid(if state.muted {
+				Color::Color(0, 1.0)
+			}
+			else {
+				Color::Color(120, if state.audible(any_solo) { 0.7 } else { 0.1 })
+			}));
+			array[x][SOLO_Y as usize] =
+				Some(Solid(Color::Color(60, if state.solo { 1.0 } else { 0.1 })));
+			array[x][ROUTE_Y as usize] = Some(Solid(Color::Color(
+				180,
+				if state.route_when_muted { 1.0 } else { 0.1 }
+			)));
+		}
+	}
+}
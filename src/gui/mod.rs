@@ -2,19 +2,31 @@
 
 use crate::arpeggiator::*;
 use crate::grid_controllers::*;
-use crate::midi::Note;
+use crate::midi::{Channel, Note};
+use crate::midi_learn::CcBinding;
 
+mod arp_presets;
+mod chain;
+mod channels;
 mod clock_division;
 mod config;
 mod edit;
+mod euclid;
+mod mixer;
 mod pattern_select;
 mod routing;
 mod scale_select;
 mod sliders;
+mod step_edit;
 
+use arp_presets::ArpPresetScreen;
+use chain::ChainScreen;
+use channels::ChannelScreen;
 use clock_division::ClockDivisionScreen;
 use config::ConfigScreen;
 use edit::EditScreen;
+use euclid::EuclidScreen;
+use mixer::MixerScreen;
 use pattern_select::PatternSelectScreen;
 use routing::RoutingScreen;
 use scale_select::ScaleSelectScreen;
@@ -27,12 +39,99 @@ enum ScreenOverlay {
 	Config(ConfigScreen),
 	ScaleSelect(ScaleSelectScreen),
 	ClockDivision(ClockDivisionScreen),
+	Channels(ChannelScreen),
+	Euclid(EuclidScreen),
+	ArpPresets(ArpPresetScreen),
+	Chain(ChainScreen),
+	Mixer(MixerScreen),
 	None
 }
 
+/// The right column button that opens the tool screens, which are selected with the top row
+const TOOLS_Y: u8 = 6;
+const N_TOOLS: u8 = 5;
+
+fn tool_screen(page: u8) -> ScreenOverlay {
+	match page {
+		0 => ScreenOverlay::Channels(ChannelScreen::new()),
+		1 => ScreenOverlay::Euclid(EuclidScreen::new()),
+		2 => ScreenOverlay::ArpPresets(ArpPresetScreen::new()),
+		3 => ScreenOverlay::Chain(ChainScreen::new()),
+		_ => ScreenOverlay::Mixer(MixerScreen::new())
+	}
+}
+
+/// The application state that the GUI changes on input. Everything specific to an
+/// arpeggiator belongs to the active one, unless it is a slice over all of them.
+pub struct InputContext<'a> {
+	pub pattern: &'a mut ArpeggioData,
+	pub n_patterns: usize,
+	pub active_pattern: &'a mut [usize],
+	pub active_arp: &'a mut usize,
+	pub launch_quantization: &'a mut LaunchQuantization,
+	pub restart_transport_pending: &'a mut bool,
+	pub stop_transport_pending: &'a mut bool,
+	pub continue_transport_pending: &'a mut bool,
+	pub use_external_clock: bool,
+	pub clock_mode: &'a mut ClockMode,
+	pub time_between_midiclocks: &'a mut u64,
+	pub ticks_per_step: &'a mut u32,
+	pub chord_hold: &'a mut bool,
+	pub latch: &'a mut bool,
+	pub in_channel: &'a mut Channel,
+	pub omni: &'a mut bool,
+	pub out_channel: &'a mut Channel,
+	pub velocity_source: &'a mut VelocitySource,
+	pub chord_order: &'a mut ChordOrder,
+	pub fill: &'a mut bool,
+	pub chord_settle_time: &'a mut u64,
+	pub scale: &'a mut heapless::Vec<Note, 16>,
+	pub scale_base_override: &'a mut Option<Note>,
+	pub fader_values: &'a mut [Option<(&'a mut f32, std::ops::RangeInclusive<f32>)>],
+	pub cc_bindings: &'a mut [Option<CcBinding>],
+	pub routing_matrix: &'a mut [Vec<bool>],
+	pub chain: &'a mut Chain,
+	pub mixer: &'a mut [MixerState],
+	pub time: u64
+}
+
+/// The application state that the GUI displays, like `InputContext`
+pub struct DrawContext<'a> {
+	pub pattern: &'a ArpeggioData,
+	pub active_pattern: &'a [usize],
+	pub queued_pattern: &'a [Option<usize>],
+	pub active_arp: usize,
+	pub launch_quantization: LaunchQuantization,
+	pub step: f32,
+	pub total_step: f32,
+	pub use_external_clock: bool,
+	pub external_clock_present: bool,
+	pub clock_mode: ClockMode,
+	pub ticks_per_step: u32,
+	pub playing: bool,
+	pub chord_hold: bool,
+	pub latch: bool,
+	pub in_channel: Channel,
+	pub omni: bool,
+	pub out_channel: Channel,
+	pub velocity_source: VelocitySource,
+	pub chord_order: ChordOrder,
+	pub fill: bool,
+	pub scale: &'a heapless::Vec<Note, 16>,
+	pub scale_base_override: Option<Note>,
+	pub fader_values: &'a [Option<(f32, std::ops::RangeInclusive<f32>)>],
+	pub cc_bindings: &'a [Option<CcBinding>],
+	pub routing_matrix: &'a [Vec<bool>],
+	pub chain: &'a Chain,
+	pub mixer: &'a [MixerState],
+	pub time: u64
+}
+
 pub struct GuiController {
 	state_down_time: u64,
 	flash_scale_button_until: u64,
+	/// The tool screen that was opened last
+	tools_page: u8,
 
 	edit_screen: EditScreen,
 	screen_overlay: ScreenOverlay
@@ -44,30 +143,20 @@ impl GuiController {
 			edit_screen: EditScreen::new(),
 			flash_scale_button_until: 0,
 			screen_overlay: ScreenOverlay::None,
+			tools_page: 0,
 			state_down_time: 0
 		}
 	}
 
-	pub fn handle_input(
-		&mut self,
-		event: GridButtonEvent,
-		pattern: &mut ArpeggioData,
-		n_patterns: usize,
-		active_pattern: &mut [usize],
-		active_arp: &mut usize,
-		restart_transport_pending: &mut bool,
-		use_external_clock: bool,
-		clock_mode: &mut ClockMode,
-		time_between_midiclocks: &mut u64,
-		ticks_per_step: &mut u32,
-		chord_hold: &mut bool,
-		chord_settle_time: &mut u64,
-		scale: &mut heapless::Vec<Note, 16>,
-		scale_base_override: &mut Option<Note>,
-		fader_values: &mut [Option<(&mut f32, std::ops::RangeInclusive<f32>)>],
-		routing_matrix: &mut Vec<Vec<bool>>,
-		time: u64
-	) {
+	/// Returns the fader that incoming control changes should be bound to
+	pub fn learning_fader(&self) -> Option<usize> {
+		match self.screen_overlay {
+			ScreenOverlay::Sliders(ref screen) => screen.held_fader(),
+			_ => None
+		}
+	}
+
+	pub fn handle_input(&mut self, event: GridButtonEvent, ctx: &mut InputContext) {
 		use GridButtonEvent::*;
 
 		println!("Handle input: {:?}", event);
@@ -79,21 +168,36 @@ impl GuiController {
 			ScreenOverlay::Routing(_) => Some(3),
 			ScreenOverlay::ScaleSelect(_) => Some(4),
 			ScreenOverlay::ClockDivision(_) => Some(5),
+			ScreenOverlay::Channels(_)
+			| ScreenOverlay::Euclid(_)
+			| ScreenOverlay::ArpPresets(_)
+			| ScreenOverlay::Chain(_)
+			| ScreenOverlay::Mixer(_) => Some(TOOLS_Y),
 			ScreenOverlay::None => None
 		};
 
 		match event {
 			Down(8, 7, _) => {
-				if scale_base_override.is_none() {
-					*chord_hold = !*chord_hold;
-					*chord_settle_time = if *chord_hold { 48000 / 40 } else { 0 };
+				if ctx.scale_base_override.is_none() {
+					// cycles through off, hold and latch
+					(*ctx.chord_hold, *ctx.latch) = match (*ctx.chord_hold, *ctx.latch) {
+						(false, false) => (true, false),
+						(true, _) => (false, true),
+						(false, true) => (false, false)
+					};
+					*ctx.chord_settle_time = if *ctx.chord_hold || *ctx.latch {
+						48000 / 40
+					}
+					else {
+						0
+					};
 				}
 				else {
-					self.flash_scale_button_until = time + 2 * 48000;
+					self.flash_scale_button_until = ctx.time + 2 * 48000;
 				}
 			}
 			Down(8, y, _) => {
-				self.state_down_time = time;
+				self.state_down_time = ctx.time;
 
 				if state_y == Some(y) {
 					self.screen_overlay = ScreenOverlay::None;
@@ -115,91 +219,115 @@ impl GuiController {
 							self.screen_overlay =
 								ScreenOverlay::ClockDivision(ClockDivisionScreen::new())
 						}
+						TOOLS_Y => self.screen_overlay = tool_screen(self.tools_page),
 						_ => ()
 					}
 				}
 			}
+			Down(x, 8, _) if state_y == Some(TOOLS_Y) && x < N_TOOLS => {
+				self.tools_page = x;
+				self.screen_overlay = tool_screen(x);
+			}
 			Up(8, y, _) => {
-				if state_y == Some(y) {
-					if time > self.state_down_time + 48000 / 3 {
-						self.screen_overlay = ScreenOverlay::None;
-					}
+				if state_y == Some(y) && ctx.time > self.state_down_time + 48000 / 3 {
+					self.screen_overlay = ScreenOverlay::None;
 				}
 			}
 			event => match self.screen_overlay {
 				ScreenOverlay::None => {
-					self.edit_screen.handle_input(event, pattern, time);
+					self.edit_screen.handle_input(event, ctx.pattern, ctx.time);
 				}
 				ScreenOverlay::Config(ref mut config) => {
 					config.handle_input(
 						event,
-						pattern,
+						ctx,
 						&mut self.edit_screen.pane_height,
-						restart_transport_pending,
-						use_external_clock,
-						clock_mode,
-						time_between_midiclocks,
-						time
+						self.edit_screen.selected_row
 					);
 				}
 				ScreenOverlay::Sliders(ref mut sliders) => {
-					sliders.handle_input(event, fader_values, time);
+					sliders.handle_input(event, ctx.fader_values, ctx.cc_bindings, ctx.time);
 				}
 				ScreenOverlay::PatternSelect(ref mut screen) => {
-					screen.handle_input(event, n_patterns, active_pattern, active_arp);
+					screen.handle_input(
+						event,
+						ctx.n_patterns,
+						ctx.active_pattern,
+						ctx.active_arp,
+						ctx.launch_quantization
+					);
 				}
 				ScreenOverlay::ScaleSelect(ref mut screen) => {
-					screen.handle_input(event, scale, scale_base_override, time);
+					screen.handle_input(event, ctx.scale, ctx.scale_base_override, ctx.time);
 				}
 				ScreenOverlay::Routing(ref mut screen) => {
-					screen.handle_input(event, routing_matrix);
+					screen.handle_input(event, ctx.routing_matrix);
 				}
 				ScreenOverlay::ClockDivision(ref mut screen) => {
-					screen.handle_input(event, ticks_per_step, restart_transport_pending, time);
+					screen.handle_input(
+						event,
+						ctx.ticks_per_step,
+						ctx.restart_transport_pending,
+						ctx.time
+					);
+				}
+				ScreenOverlay::Channels(ref mut screen) => {
+					screen.handle_input(event, ctx.in_channel, ctx.omni, ctx.out_channel);
+				}
+				ScreenOverlay::Euclid(ref mut screen) => {
+					screen.handle_input(
+						event,
+						ctx.pattern,
+						self.edit_screen.selected_row,
+						self.edit_screen.current_octave,
+						self.edit_screen.current_intensity
+					);
+				}
+				ScreenOverlay::ArpPresets(ref mut screen) => {
+					screen.handle_input(
+						event,
+						ctx.pattern,
+						ctx.chord_order,
+						self.edit_screen.current_octave,
+						self.edit_screen.current_intensity,
+						ctx.time
+					);
+				}
+				ScreenOverlay::Chain(ref mut screen) => {
+					screen.handle_input(event, ctx.chain, ctx.active_pattern[*ctx.active_arp]);
+				}
+				ScreenOverlay::Mixer(ref mut screen) => {
+					screen.handle_input(event, ctx.mixer);
 				}
 			}
 		}
 
-		if !scale.is_empty() && pattern.repeat_mode != RepeatMode::Repeat(12) {
-			pattern.repeat_mode = RepeatMode::Repeat(12);
+		if !ctx.scale.is_empty() && ctx.pattern.repeat_mode != RepeatMode::Repeat(12) {
+			ctx.pattern.repeat_mode = RepeatMode::Repeat(12);
 			match self.screen_overlay {
 				ScreenOverlay::ScaleSelect(_) => (),
-				_ => self.flash_scale_button_until = time + 2 * 48000
+				_ => self.flash_scale_button_until = ctx.time + 2 * 48000
 			}
 		}
 	}
 
-	pub fn draw(
-		&mut self,
-		pattern: &ArpeggioData,
-		active_pattern: &[usize],
-		active_arp: usize,
-		step: f32,
-		use_external_clock: bool,
-		external_clock_present: bool,
-		clock_mode: ClockMode,
-		ticks_per_step: u32,
-		chord_hold: bool,
-		scale: &heapless::Vec<Note, 16>,
-		scale_base_override: Option<Note>,
-		fader_values: &[Option<(f32, std::ops::RangeInclusive<f32>)>],
-		routing_matrix: &Vec<Vec<bool>>,
-		time: u64,
-		mut set_led: impl FnMut((u8, u8), LightingMode)
-	) {
+	pub fn draw(&mut self, ctx: &DrawContext, mut set_led: impl FnMut((u8, u8), LightingMode)) {
 		use std::convert::TryInto;
 		use LightingMode::*;
 
 		const MENU_SELECTED: LightingMode = Fade(Color::Color(0, 0.74));
 
 		let mut array = [[None; 9]; 9];
-		let (right_buttons, grid_and_top) = (&mut array).split_last_mut().unwrap();
-		let grid_and_top = grid_and_top.try_into().unwrap();
+		let (right_buttons, grid_and_top) = array.split_last_mut().unwrap();
+		let grid_and_top: &mut [[Option<LightingMode>; 9]; 8] = grid_and_top.try_into().unwrap();
 
-		right_buttons[7] = Some(if scale_base_override.is_none() {
-			if chord_hold {
+		right_buttons[7] = Some(if ctx.scale_base_override.is_none() {
+			if ctx.chord_hold {
 				Solid(Color::Color(215, 0.7))
 			}
+			else if ctx.latch {
+				Solid(Color::Color(120, 0.7))
+			}
 			else {
 				Solid(Color::Color(300, 0.1))
 			}
@@ -208,8 +336,8 @@ impl GuiController {
 			Solid(Color::Color(60, 0.7))
 		});
 
-		right_buttons[4] = if time < self.flash_scale_button_until {
-			if (time / (48000 / 10)) % 2 == 0 {
+		right_buttons[4] = if ctx.time < self.flash_scale_button_until {
+			if (ctx.time / (48000 / 10)).is_multiple_of(2) {
 				Some(Off)
 			}
 			else {
@@ -217,7 +345,7 @@ impl GuiController {
 			}
 		}
 		else {
-			if scale.is_empty() {
+			if ctx.scale.is_empty() {
 				None
 			}
 			else {
@@ -227,39 +355,80 @@ impl GuiController {
 
 		match self.screen_overlay {
 			ScreenOverlay::None => {
-				self.edit_screen.draw(grid_and_top, pattern, step, time);
+				self.edit_screen.draw(
+					grid_and_top,
+					ctx.pattern,
+					ctx.step,
+					ctx.total_step,
+					ctx.time
+				);
 			}
 			ScreenOverlay::Config(ref mut screen) => {
 				right_buttons[0] = Some(MENU_SELECTED);
 				screen.draw(
 					grid_and_top,
-					pattern,
+					ctx,
 					self.edit_screen.pane_height,
-					use_external_clock,
-					external_clock_present,
-					clock_mode,
-					time
+					self.edit_screen.selected_row
 				);
 			}
 			ScreenOverlay::Sliders(ref mut screen) => {
 				right_buttons[1] = Some(MENU_SELECTED);
-				screen.draw(grid_and_top, fader_values);
+				screen.draw(grid_and_top, ctx.fader_values, ctx.cc_bindings);
 			}
 			ScreenOverlay::PatternSelect(ref mut screen) => {
 				right_buttons[2] = Some(MENU_SELECTED);
-				screen.draw(grid_and_top, active_pattern, active_arp)
+				screen.draw(
+					grid_and_top,
+					ctx.active_pattern,
+					ctx.queued_pattern,
+					ctx.active_arp,
+					ctx.launch_quantization
+				)
 			}
 			ScreenOverlay::Routing(ref mut screen) => {
 				right_buttons[3] = Some(MENU_SELECTED);
-				screen.draw(grid_and_top, routing_matrix);
+				screen.draw(grid_and_top, ctx.routing_matrix);
 			}
 			ScreenOverlay::ScaleSelect(ref mut screen) => {
 				right_buttons[4] = Some(MENU_SELECTED);
-				screen.draw(grid_and_top, scale, scale_base_override);
+				screen.draw(grid_and_top, ctx.scale, ctx.scale_base_override);
 			}
 			ScreenOverlay::ClockDivision(ref mut screen) => {
 				right_buttons[5] = Some(MENU_SELECTED);
-				screen.draw(grid_and_top, ticks_per_step, step as u32, time);
+				screen.draw(grid_and_top, ctx.ticks_per_step, ctx.step as u32, ctx.time);
+			}
+			ScreenOverlay::Channels(ref mut screen) => {
+				screen.draw(grid_and_top, ctx.in_channel, ctx.omni, ctx.out_channel);
+			}
+			ScreenOverlay::Euclid(ref mut screen) => {
+				screen.draw(grid_and_top);
+			}
+			ScreenOverlay::ArpPresets(ref mut screen) => {
+				screen.draw(grid_and_top);
+			}
+			ScreenOverlay::Chain(ref mut screen) => {
+				screen.draw(grid_and_top, ctx.chain);
+			}
+			ScreenOverlay::Mixer(ref mut screen) => {
+				screen.draw(grid_and_top, ctx.mixer);
+			}
+		}
+
+		if let ScreenOverlay::Channels(_)
+		| ScreenOverlay::Euclid(_)
+		| ScreenOverlay::ArpPresets(_)
+		| ScreenOverlay::Chain(_)
+		| ScreenOverlay::Mixer(_) = self.screen_overlay
+		{
+			right_buttons[TOOLS_Y as usize] = Some(MENU_SELECTED);
+			for x in 0..N_TOOLS {
+				grid_and_top[x as usize][8] = Some(Solid(if x == self.tools_page {
+					Color::White(1.0)
+				}
+				else {
+					Color::Color(0, 0.3)
+				}));
 			}
 		}
 
@@ -1,9 +1,22 @@
 // this file is part of arpfisch. For copyright and licensing details, see main.rs
 
+use crate::arpeggiator::LaunchQuantization;
 use crate::grid_controllers::{Color, GridButtonEvent, LightingMode};
 
 pub struct PatternSelectScreen {}
 
+/// The launch quantizations selectable in the top row
+fn launch_quantization_at(x: u8) -> Option<LaunchQuantization> {
+	use LaunchQuantization::*;
+	match x {
+		0 => Some(Immediate),
+		1 => Some(NextStep),
+		2 => Some(PatternEnd),
+		3..=7 => Some(Bar(4 << (x - 3))),
+		_ => None
+	}
+}
+
 impl PatternSelectScreen {
 	pub fn new() -> PatternSelectScreen { PatternSelectScreen {} }
 
@@ -12,13 +25,19 @@ impl PatternSelectScreen {
 		event: GridButtonEvent,
 		n_patterns: usize,
 		active_pattern: &mut [usize],
-		active_arp: &mut usize
+		active_arp: &mut usize,
+		launch_quantization: &mut LaunchQuantization
 	) {
 		use GridButtonEvent::*;
 
 		let n_arps = active_pattern.len();
 
 		match event {
+			Down(x, 8, _) => {
+				if let Some(quantization) = launch_quantization_at(x) {
+					*launch_quantization = quantization;
+				}
+			}
 			Down(x, y, _) if x < 8 && y < 8 => {
 				let x = x as usize;
 				let y = y as usize;
@@ -37,7 +56,9 @@ impl PatternSelectScreen {
 		&mut self,
 		array: &mut [[Option<LightingMode>; 9]; 8],
 		active_pattern: &[usize],
-		active_arp: usize
+		queued_pattern: &[Option<usize>],
+		active_arp: usize,
+		launch_quantization: LaunchQuantization
 	) {
 		use LightingMode::*;
 
@@ -45,15 +66,38 @@ impl PatternSelectScreen {
 
 		for y in 0..n_arps.min(8) {
 			let y = y as usize;
+			let color = Color::Color((360 * y as u16 * 3 / 8) % 360, 0.7);
 
 			if active_pattern[y] < 8 {
 				array[active_pattern[y]][y] = Some(if y == active_arp {
 					Fade(Color::White(1.0))
 				}
 				else {
-					Solid(Color::Color((360 * y as u16 * 3 / 8) % 360, 0.7))
+					Solid(color)
 				})
 			}
+			// the pattern that will be switched to
+			if let Some(pattern) = queued_pattern.get(y).copied().flatten() {
+				if pattern < 8 {
+					array[pattern][y] = Some(Blink(if y == active_arp {
+						Color::White(1.0)
+					}
+					else {
+						color
+					}));
+				}
+			}
+		}
+
+		for x in 0..8 {
+			if let Some(quantization) = launch_quantization_at(x) {
+				array[x as usize][8] = Some(Solid(if quantization == launch_quantization {
+					Color::White(1.0)
+				}
+				else {
+					Color::Color(180, 0.2 + 0.1 * x as f32)
+				}));
+			}
 		}
 	}
 }
@@ -7,27 +7,23 @@ pub struct RoutingScreen {}
 impl RoutingScreen {
 	pub fn new() -> RoutingScreen { RoutingScreen {} }
 
-	pub fn handle_input(&mut self, event: GridButtonEvent, routing_matrix: &mut Vec<Vec<bool>>) {
+	pub fn handle_input(&mut self, event: GridButtonEvent, routing_matrix: &mut [Vec<bool>]) {
 		use GridButtonEvent::*;
 
 		assert!(routing_matrix.len() == routing_matrix[0].len());
 		let len = routing_matrix.len();
 
-		match event {
-			Down(x, y, _) => {
-				if x < y && (y as usize) < len {
-					routing_matrix[x as usize][y as usize] =
-						!routing_matrix[x as usize][y as usize];
-				}
+		if let Down(x, y, _) = event {
+			if x < y && (y as usize) < len {
+				routing_matrix[x as usize][y as usize] = !routing_matrix[x as usize][y as usize];
 			}
-			_ => ()
 		}
 	}
 
 	pub fn draw(
 		&mut self,
 		array: &mut [[Option<LightingMode>; 9]; 8],
-		routing_matrix: &Vec<Vec<bool>>
+		routing_matrix: &[Vec<bool>]
 	) {
 		use LightingMode::*;
 
@@ -1,6 +1,7 @@
 // this file is part of arpfisch. For copyright and licensing details, see main.rs
 
 use crate::grid_controllers::{Color, GridButtonEvent, LightingMode};
+use crate::midi_learn::{CcBinding, CcMode};
 
 pub struct SlidersScreen {
 	down_times: [[Option<u64>; 8]; 8],
@@ -17,14 +18,38 @@ impl SlidersScreen {
 		}
 	}
 
+	/// Returns the fader whose column is currently held down, for MIDI learn
+	pub fn held_fader(&self) -> Option<usize> {
+		self.down_times
+			.iter()
+			.position(|column| column.iter().any(|down_time| down_time.is_some()))
+	}
+
 	pub fn handle_input(
 		&mut self,
 		event: GridButtonEvent,
 		fader_values: &mut [Option<(&mut f32, std::ops::RangeInclusive<f32>)>],
+		cc_bindings: &mut [Option<CcBinding>],
 		time: u64
 	) {
 		use GridButtonEvent::*;
 
+		// the top row cycles through the modes of learned bindings, and removes them
+		if let Down(x, 8, _) = event {
+			if let Some(binding) = cc_bindings.get_mut(x as usize) {
+				*binding = match *binding {
+					Some(CcBinding {
+						controller,
+						mode: CcMode::SoftTakeover
+					}) => Some(CcBinding {
+						controller,
+						mode: CcMode::Relative
+					}),
+					_ => None
+				};
+			}
+		}
+
 		match event {
 			Down(x, y, _) => {
 				if x < 8 && y < 8 {
@@ -86,10 +111,18 @@ impl SlidersScreen {
 	pub fn draw(
 		&mut self,
 		array: &mut [[Option<LightingMode>; 9]; 8],
-		fader_values: &[Option<(f32, std::ops::RangeInclusive<f32>)>]
+		fader_values: &[Option<(f32, std::ops::RangeInclusive<f32>)>],
+		cc_bindings: &[Option<CcBinding>]
 	) {
 		use LightingMode::*;
 
+		for (x, binding) in cc_bindings.iter().enumerate() {
+			array[x][8] = binding.map(|binding| match binding.mode {
+				CcMode::SoftTakeover => Solid(Color::White(0.7)),
+				CcMode::Relative => Solid(Color::Color(60, 0.7))
+			});
+		}
+
 		for (x, fader) in fader_values.iter().enumerate() {
 			if let Some((value, range)) = fader {
 				let leds =
@@ -0,0 +1,314 @@
+// this file is part of arpfisch. For copyright and licensing details, see main.rs
+
+use crate::arpeggiator::{ArpeggioData, Condition, Entry, MAX_RATCHETS};
+use crate::grid_controllers::{Color, GridButtonEvent, LightingMode};
+
+use super::edit::transpose_hue;
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum StepEditPage {
+	Probability,
+	Ratchets,
+	Timing,
+	Condition,
+	Degrees,
+	Transpose
+}
+
+const RATCHET_RAMP_Y: u8 = 2;
+/// The button for an even velocity in the ratchet ramp row
+const RATCHET_RAMP_CENTER: u8 = 4;
+
+/// The rows for raising and lowering the note by up to 7 scale degrees, with 0 on the left
+const DEGREES_UP_Y: u8 = 1;
+const DEGREES_DOWN_Y: u8 = 0;
+
+/// The semitone picker shows a piano keyboard for each octave from -1 to 2, with the white
+/// keys in the lower and the black keys in the upper row of each pair of rows.
+fn transpose_at(x: u8, y: u8) -> Option<i32> {
+	const WHITE_KEYS: [i32; 7] = [0, 2, 4, 5, 7, 9, 11];
+	const BLACK_KEYS: [Option<i32>; 7] = [None, Some(1), Some(3), None, Some(6), Some(8), Some(10)];
+	let semitone = if y.is_multiple_of(2) {
+		WHITE_KEYS.get(x as usize).copied()
+	}
+	else {
+		BLACK_KEYS.get(x as usize).copied().flatten()
+	}?;
+	Some(12 * (y as i32 / 2 - 1) + semitone)
+}
+
+/// The conditions in the top row of the condition page. Below, each row `y` holds the
+/// ratios `a:b` with `b = 8 - y`.
+const CONDITIONS: [Condition; 7] = [
+	Condition::Always,
+	Condition::First,
+	Condition::NotFirst,
+	Condition::Fill,
+	Condition::NotFill,
+	Condition::Previous,
+	Condition::NotPrevious
+];
+
+fn condition_at(x: u8, y: u8) -> Option<Condition> {
+	if y == 7 {
+		CONDITIONS.get(x as usize).copied()
+	}
+	else if x < 8 - y {
+		Some(Condition::Ratio(x + 1, 8 - y))
+	}
+	else {
+		None
+	}
+}
+
+impl StepEditPage {
+	/// Maps the top row buttons to the pages
+	pub fn from_button(x: u8) -> Option<StepEditPage> {
+		match x {
+			0 => Some(StepEditPage::Probability),
+			1 => Some(StepEditPage::Ratchets),
+			2 => Some(StepEditPage::Timing),
+			3 => Some(StepEditPage::Condition),
+			4 => Some(StepEditPage::Degrees),
+			5 => Some(StepEditPage::Transpose),
+			_ => None
+		}
+	}
+
+	fn button(&self) -> u8 {
+		match self {
+			StepEditPage::Probability => 0,
+			StepEditPage::Ratchets => 1,
+			StepEditPage::Timing => 2,
+			StepEditPage::Condition => 3,
+			StepEditPage::Degrees => 4,
+			StepEditPage::Transpose => 5
+		}
+	}
+}
+
+/// Edits the properties of all entries of a single note in a single step, which
+/// are not visible in the main view.
+pub struct StepEditScreen {
+	pos: usize,
+	note: isize,
+	page: StepEditPage
+}
+
+impl StepEditScreen {
+	pub fn new(pos: usize, note: isize, page: StepEditPage) -> StepEditScreen {
+		StepEditScreen { pos, note, page }
+	}
+
+	fn for_each_entry(&self, pattern: &mut ArpeggioData, f: impl FnMut(&mut Entry)) {
+		pattern.filter_mut(self.pos, self.note).for_each(f);
+	}
+
+	/// Adds an entry with the given transposition, copying the properties of the existing ones,
+	/// or removes it if it exists. The last entry is never removed.
+	fn toggle_transpose(&self, pattern: &mut ArpeggioData, transpose: i32) {
+		let entry_opt = pattern
+			.filter(self.pos, self.note)
+			.find(|e| e.transpose == transpose)
+			.cloned();
+		if let Some(entry) = entry_opt {
+			if pattern.filter(self.pos, self.note).count() > 1 {
+				pattern.delete(self.pos, entry);
+			}
+		}
+		else {
+			let template = pattern.filter(self.pos, self.note).next().cloned();
+			if let Some(mut new_entry) = template {
+				new_entry.transpose = transpose;
+				pattern.set(self.pos, new_entry).ok(); // all we can do is ignore an error
+			}
+		}
+	}
+
+	/// Returns false if the screen should be closed.
+	pub fn handle_input(&mut self, event: GridButtonEvent, pattern: &mut ArpeggioData) -> bool {
+		use GridButtonEvent::*;
+
+		if self.pos >= pattern.pattern.len() || pattern.filter(self.pos, self.note).count() == 0 {
+			return false;
+		}
+
+		match event {
+			Down(x, 8, _) => {
+				if x == self.page.button() {
+					return false;
+				}
+				if let Some(page) = StepEditPage::from_button(x) {
+					self.page = page;
+				}
+			}
+			Down(x, y, _) if x < 8 && y < 8 => match self.page {
+				StepEditPage::Probability => {
+					if y == 0 {
+						self.for_each_entry(pattern, |e| e.probability = (x + 1) as f32 / 8.0);
+					}
+				}
+				StepEditPage::Ratchets => {
+					if y == 0 && (x as u32) < MAX_RATCHETS {
+						self.for_each_entry(pattern, |e| e.ratchets = x as u32 + 1);
+					}
+					if y == RATCHET_RAMP_Y && x > 0 {
+						let ramp = (x as f32 - RATCHET_RAMP_CENTER as f32) / 3.0;
+						self.for_each_entry(pattern, |e| e.ratchet_ramp = ramp);
+					}
+				}
+				StepEditPage::Timing => {
+					if y == 0 {
+						self.for_each_entry(pattern, |e| e.delay = x as f32 / 8.0);
+					}
+				}
+				StepEditPage::Condition => {
+					if let Some(condition) = condition_at(x, y) {
+						self.for_each_entry(pattern, |e| e.condition = condition);
+					}
+				}
+				StepEditPage::Degrees => {
+					if y == DEGREES_UP_Y {
+						self.for_each_entry(pattern, |e| e.degrees = x as i32);
+					}
+					if y == DEGREES_DOWN_Y {
+						self.for_each_entry(pattern, |e| e.degrees = -(x as i32));
+					}
+				}
+				StepEditPage::Transpose => {
+					if let Some(transpose) = transpose_at(x, y) {
+						self.toggle_transpose(pattern, transpose);
+					}
+				}
+			},
+			_ => ()
+		}
+		true
+	}
+
+	pub fn draw(&mut self, array: &mut [[Option<LightingMode>; 9]; 8], pattern: &ArpeggioData) {
+		use LightingMode::*;
+
+		for x in 0..8 {
+			if let Some(page) = StepEditPage::from_button(x) {
+				array[x as usize][8] = Some(Solid(Color::Color(
+					page_hue(page),
+					if page == self.page { 1.0 } else { 0.2 }
+				)));
+			}
+		}
+
+		let entry = match pattern.filter(self.pos, self.note).next() {
+			Some(entry) => entry,
+			None => return
+		};
+
+		match self.page {
+			StepEditPage::Probability => {
+				let n_lit = (entry.probability * 8.0).round() as usize;
+				for (x, column) in array.iter_mut().enumerate() {
+					column[0] = Some(Solid(Color::Color(
+						page_hue(self.page),
+						if x < n_lit { 0.8 } else { 0.1 }
+					)));
+				}
+			}
+			StepEditPage::Ratchets => {
+				for (x, column) in array.iter_mut().enumerate().take(MAX_RATCHETS as usize) {
+					column[0] = Some(Solid(Color::Color(
+						page_hue(self.page),
+						if x < entry.ratchets as usize {
+							0.8
+						}
+						else {
+							0.1
+						}
+					)));
+				}
+				let selected =
+					(RATCHET_RAMP_CENTER as f32 + entry.ratchet_ramp * 3.0).round() as usize;
+				for (x, column) in array.iter_mut().enumerate().skip(1) {
+					column[RATCHET_RAMP_Y as usize] = Some(Solid(if x == selected {
+						Color::White(1.0)
+					}
+					else if x == RATCHET_RAMP_CENTER as usize {
+						Color::White(0.3)
+					}
+					else {
+						Color::Color(page_hue(self.page), 0.1 + 0.1 * x as f32)
+					}));
+				}
+			}
+			StepEditPage::Timing => {
+				let selected = (entry.delay * 8.0).round() as usize;
+				for (x, column) in array.iter_mut().enumerate() {
+					column[0] = Some(Solid(if x == selected {
+						Color::White(1.0)
+					}
+					else {
+						Color::Color(page_hue(self.page), 0.2)
+					}));
+				}
+			}
+			StepEditPage::Condition => {
+				for x in 0..8 {
+					for y in 0..8 {
+						if let Some(condition) = condition_at(x, y) {
+							array[x as usize][y as usize] =
+								Some(Solid(if condition == entry.condition {
+									Color::White(1.0)
+								}
+								else if y == 7 {
+									Color::Color(page_hue(self.page), 0.4)
+								}
+								else {
+									Color::Color(330, 0.1 + 0.1 * (x % 2) as f32)
+								}));
+						}
+					}
+				}
+			}
+			StepEditPage::Degrees => {
+				for (x, column) in array.iter_mut().enumerate() {
+					for (y, degrees) in [(DEGREES_UP_Y, x as i32), (DEGREES_DOWN_Y, -(x as i32))] {
+						column[y as usize] = Some(Solid(if degrees == entry.degrees {
+							Color::White(1.0)
+						}
+						else if x == 0 {
+							Color::White(0.3)
+						}
+						else {
+							Color::Color(page_hue(self.page), 0.1 + 0.1 * x as f32)
+						}));
+					}
+				}
+			}
+			StepEditPage::Transpose => {
+				for x in 0..8 {
+					for y in 0..8 {
+						if let Some(transpose) = transpose_at(x, y) {
+							let active = pattern
+								.filter(self.pos, self.note)
+								.any(|e| e.transpose == transpose);
+							array[x as usize][y as usize] = Some(Solid(Color::Color(
+								transpose_hue(transpose),
+								if active { 1.0 } else { 0.1 }
+							)));
+						}
+					}
+				}
+			}
+		}
+	}
+}
+
+fn page_hue(page: StepEditPage) -> u16 {
+	match page {
+		StepEditPage::Probability => 120,
+		StepEditPage::Ratchets => 30,
+		StepEditPage::Timing => 200,
+		StepEditPage::Condition => 270,
+		StepEditPage::Degrees => 90,
+		StepEditPage::Transpose => 330
+	}
+}
@@ -17,16 +17,23 @@
 mod application;
 mod arpeggiator;
 mod driver;
+mod generators;
 mod grid_controllers;
 mod gui;
 mod midi;
+mod midi_learn;
+mod osc;
+mod random;
 mod tempo_detector;
 
 use application::ArpApplication;
+use driver::alsa::AlsaDriver;
 use driver::jack::JackDriver;
+use driver::offline::OfflineDriver;
+use osc::OscServer;
 use std::io::Write;
 use std::thread;
-use clap::Parser;
+use clap::{Parser, Subcommand, ValueEnum};
 
 #[cfg(debug_assertions)] // required when disable_release is set (default)
 #[global_allocator]
@@ -44,13 +51,42 @@ fn temp_name(filename: &Path) -> PathBuf {
 }
 
 
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum Driver {
+	Jack,
+	Alsa
+}
+
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = Some("A MIDI arpeggiator and step sequencer"))]
 struct Args {
-	#[clap(short = 'n', long, default_value = "arpfisch")]
-	jack_client_name: String,
+	/// Name of the JACK client or ALSA sequencer client
+	#[clap(short = 'n', long, alias = "jack-client-name", default_value = "arpfisch")]
+	client_name: String,
+
+	/// Audio system to connect to
+	#[clap(short, long, value_enum, default_value = "jack")]
+	driver: Driver,
+
+	/// Accept OSC messages for remote control on this UDP port on localhost
+	#[clap(long)]
+	osc_port: Option<u16>,
+
+	filename: String,
 
-	filename: String
+	#[clap(subcommand)]
+	command: Option<Command>
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+	/// Render a MIDI file through the arpeggiators into another MIDI file, without
+	/// connecting to any audio system. The file's tempo is followed unless the clock
+	/// mode is set to internal.
+	Render {
+		input: PathBuf,
+		output: PathBuf
+	}
 }
 
 
@@ -67,7 +103,7 @@ fn main() {
 		.unwrap();
 
 
-	let app = match std::fs::File::open(args.filename.clone()) {
+	let mut app = match std::fs::File::open(args.filename.clone()) {
 		Ok(file) => {
 			ArpApplication::from_reader(file, save_send_consumer, save_return_producer).expect("Failed to load file")
 		}
@@ -76,7 +112,19 @@ fn main() {
 			_ => panic!("Failed to open file for reading")
 		}
 	};
-	
+
+	if let Some(port) = args.osc_port {
+		let (request_producer, request_consumer) = ringbuf::RingBuffer::new(256).split();
+		let (reply_producer, reply_consumer) = ringbuf::RingBuffer::new(256).split();
+		OscServer::spawn(port, request_producer, reply_consumer)
+			.expect("Failed to start the OSC server");
+		app.connect_osc(request_consumer, reply_producer);
+	}
+
+	if let Some(Command::Render { input, output }) = args.command {
+		OfflineDriver::render(&input, &output, app).expect("Failed to render");
+		return;
+	}
 
 	let filename = args.filename.clone();
 	thread::spawn(move || {
@@ -98,8 +146,8 @@ fn main() {
 	});
 
 
-	JackDriver::run(
-		&args.jack_client_name,
-		app
-	);
+	match args.driver {
+		Driver::Jack => JackDriver::run(&args.client_name, app),
+		Driver::Alsa => AlsaDriver::run(&args.client_name, app)
+	}
 }
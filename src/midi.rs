@@ -5,7 +5,7 @@ use serde::{Deserialize, Serialize};
 #[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug, Serialize, Deserialize)]
 pub struct Note(pub u8);
 
-#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug, Serialize, Deserialize)]
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug, Default, Serialize, Deserialize)]
 pub struct Channel(pub u8);
 
 impl Note {
@@ -20,44 +20,134 @@ impl Note {
 	}
 }
 
+/// Maximum length of a MIDI message, except for SysEx messages
+pub const MAX_MESSAGE_LEN: usize = 3;
+
+/// Release velocity of the note offs that don't have a velocity of their own
+pub const DEFAULT_RELEASE_VELOCITY: u8 = 64;
+
+/// Whether `bytes` is a complete System Exclusive message, including the leading `0xF0`
+/// and trailing `0xF7`. These are no [`MidiEvent`]s, the drivers pass them through from
+/// each arpeggiator's input to its output unchanged.
+pub fn is_sysex(bytes: &[u8]) -> bool {
+	bytes.len() >= 2
+		&& bytes[0] == 0xF0
+		&& bytes[bytes.len() - 1] == 0xF7
+		&& bytes[1..bytes.len() - 1].iter().all(|b| *b < 0x80)
+}
+
+/// The variant order matters: events with the same time stamp are sent in this order.
 #[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
 pub enum MidiEvent {
 	NoteOn(Note, u8, Channel),
-	NoteOff(Note, Channel),
+	/// Note and release velocity
+	NoteOff(Note, u8, Channel),
 	Clock,
-	Start
+	Stop,
+	/// Position in MIDI beats (sixteenth notes) since the start of the song
+	SongPosition(u16),
+	Start,
+	Continue,
+	PolyAftertouch(Note, u8, Channel),
+	ControlChange(u8, u8, Channel),
+	ProgramChange(u8, Channel),
+	ChannelAftertouch(u8, Channel),
+	/// 14 bit value, centered at `0x2000`
+	PitchBend(u16, Channel)
 }
 
 impl MidiEvent {
-	pub fn to_bytes(&self) -> heapless::Vec<u8, 3> {
+	pub fn to_bytes(&self) -> heapless::Vec<u8, MAX_MESSAGE_LEN> {
 		use MidiEvent::*;
 		match self {
 			NoteOn(note, velo, channel) => {
 				heapless::Vec::from_slice(&[0x90 | channel.0, note.0, *velo])
 			}
-			NoteOff(note, channel) => heapless::Vec::from_slice(&[0x80 | channel.0, note.0, 64]),
+			NoteOff(note, velo, channel) => {
+				heapless::Vec::from_slice(&[0x80 | channel.0, note.0, *velo])
+			}
+			PolyAftertouch(note, pressure, channel) => {
+				heapless::Vec::from_slice(&[0xA0 | channel.0, note.0, *pressure])
+			}
+			ControlChange(controller, value, channel) => {
+				heapless::Vec::from_slice(&[0xB0 | channel.0, *controller, *value])
+			}
+			ProgramChange(program, channel) => {
+				heapless::Vec::from_slice(&[0xC0 | channel.0, *program])
+			}
+			ChannelAftertouch(pressure, channel) => {
+				heapless::Vec::from_slice(&[0xD0 | channel.0, *pressure])
+			}
+			PitchBend(value, channel) => heapless::Vec::from_slice(&[
+				0xE0 | channel.0,
+				(value & 0x7F) as u8,
+				(value >> 7) as u8
+			]),
+			SongPosition(position) => {
+				heapless::Vec::from_slice(&[0xF2, (position & 0x7F) as u8, (position >> 7) as u8])
+			}
 			Clock => heapless::Vec::from_slice(&[0xF8]),
-			Start => heapless::Vec::from_slice(&[0xFA])
+			Start => heapless::Vec::from_slice(&[0xFA]),
+			Continue => heapless::Vec::from_slice(&[0xFB]),
+			Stop => heapless::Vec::from_slice(&[0xFC])
 		}
 		.unwrap()
 	}
 
+	/// Parses a single, complete MIDI message. Returns `None` for unsupported or malformed
+	/// messages, including messages with too few or too many bytes and SysEx messages.
 	pub fn parse(bytes: &[u8]) -> Option<MidiEvent> {
 		use MidiEvent::*;
-		if bytes[0] & 0xF0 == 0x90 {
-			Some(NoteOn(Note(bytes[1]), bytes[2], Channel(bytes[0] & 0x0F)))
-		}
-		else if bytes[0] & 0xF0 == 0x80 {
-			Some(NoteOff(Note(bytes[1]), Channel(bytes[0] & 0x0F)))
-		}
-		else if bytes[0] == 0xFA {
-			Some(Start)
+
+		let status = *bytes.first()?;
+		let channel = Channel(status & 0x0F);
+		let expected_len = match status {
+			0x80..=0xBF | 0xE0..=0xEF | 0xF2 => 3,
+			0xC0..=0xDF => 2,
+			_ => 1
+		};
+		if bytes.len() != expected_len || bytes[1..].iter().any(|b| *b >= 0x80) {
+			return None;
 		}
-		else if bytes[0] == 0xF8 {
-			Some(Clock)
+
+		match status & 0xF0 {
+			0x80 => Some(NoteOff(Note(bytes[1]), bytes[2], channel)),
+			0x90 => {
+				if bytes[2] == 0 {
+					Some(NoteOff(Note(bytes[1]), DEFAULT_RELEASE_VELOCITY, channel))
+				}
+				else {
+					Some(NoteOn(Note(bytes[1]), bytes[2], channel))
+				}
+			}
+			0xA0 => Some(PolyAftertouch(Note(bytes[1]), bytes[2], channel)),
+			0xB0 => Some(ControlChange(bytes[1], bytes[2], channel)),
+			0xC0 => Some(ProgramChange(bytes[1], channel)),
+			0xD0 => Some(ChannelAftertouch(bytes[1], channel)),
+			0xE0 => Some(PitchBend(bytes[1] as u16 | (bytes[2] as u16) << 7, channel)),
+			_ => match status {
+				0xF2 => Some(SongPosition(bytes[1] as u16 | (bytes[2] as u16) << 7)),
+				0xF8 => Some(Clock),
+				0xFA => Some(Start),
+				0xFB => Some(Continue),
+				0xFC => Some(Stop),
+				_ => None
+			}
 		}
-		else {
-			None
+	}
+
+	/// Returns the channel of channel messages, or `None` for system messages.
+	pub fn channel(&self) -> Option<Channel> {
+		use MidiEvent::*;
+		match *self {
+			NoteOn(_, _, channel)
+			| NoteOff(_, _, channel)
+			| PolyAftertouch(_, _, channel)
+			| ControlChange(_, _, channel)
+			| ProgramChange(_, channel)
+			| ChannelAftertouch(_, channel)
+			| PitchBend(_, channel) => Some(channel),
+			_ => None
 		}
 	}
 
@@ -65,8 +155,71 @@ impl MidiEvent {
 		use MidiEvent::*;
 		match self {
 			NoteOn(note, velo, _) => NoteOn(note, velo, channel),
-			NoteOff(note, _) => NoteOff(note, channel),
+			NoteOff(note, velo, _) => NoteOff(note, velo, channel),
+			PolyAftertouch(note, pressure, _) => PolyAftertouch(note, pressure, channel),
+			ControlChange(controller, value, _) => ControlChange(controller, value, channel),
+			ProgramChange(program, _) => ProgramChange(program, channel),
+			ChannelAftertouch(pressure, _) => ChannelAftertouch(pressure, channel),
+			PitchBend(value, _) => PitchBend(value, channel),
 			other => other
 		}
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	pub fn round_trip() {
+		let events = [
+			MidiEvent::NoteOn(Note(60), 100, Channel(3)),
+			MidiEvent::NoteOff(Note(61), 0, Channel(15)),
+			MidiEvent::NoteOff(Note(61), 100, Channel(15)),
+			MidiEvent::PolyAftertouch(Note(62), 17, Channel(0)),
+			MidiEvent::ControlChange(74, 127, Channel(1)),
+			MidiEvent::ProgramChange(5, Channel(2)),
+			MidiEvent::ChannelAftertouch(99, Channel(4)),
+			MidiEvent::PitchBend(0x2000, Channel(5)),
+			MidiEvent::PitchBend(0x3FFF, Channel(6)),
+			MidiEvent::SongPosition(0x1234),
+			MidiEvent::Clock,
+			MidiEvent::Start,
+			MidiEvent::Continue,
+			MidiEvent::Stop
+		];
+
+		for event in events.iter() {
+			assert_eq!(MidiEvent::parse(&event.to_bytes()), Some(*event));
+		}
+	}
+
+	#[test]
+	pub fn note_on_with_zero_velocity_is_note_off() {
+		assert_eq!(
+			MidiEvent::parse(&[0x92, 60, 0]),
+			Some(MidiEvent::NoteOff(
+				Note(60),
+				DEFAULT_RELEASE_VELOCITY,
+				Channel(2)
+			))
+		);
+	}
+
+	#[test]
+	pub fn malformed_messages() {
+		assert_eq!(MidiEvent::parse(&[]), None);
+		assert_eq!(MidiEvent::parse(&[0x90]), None);
+		assert_eq!(MidiEvent::parse(&[0x90, 60]), None);
+		assert_eq!(MidiEvent::parse(&[0x90, 60, 100, 0]), None);
+		assert_eq!(MidiEvent::parse(&[0x90, 0x80, 100]), None);
+		assert_eq!(MidiEvent::parse(&[0xC0]), None);
+		assert_eq!(MidiEvent::parse(&[0xF2, 0]), None);
+		assert_eq!(MidiEvent::parse(&[0xF8, 0]), None);
+		assert_eq!(MidiEvent::parse(&[0xF0, 0x01, 0x02]), None);
+		assert_eq!(MidiEvent::parse(&[0xF0, 0x00, 0x20, 0x29, 0xF7]), None);
+		assert!(is_sysex(&[0xF0, 0x00, 0x20, 0x29, 0xF7]));
+		assert!(!is_sysex(&[0xF0, 0x01, 0x02]));
+		assert_eq!(MidiEvent::parse(&[0x12, 0x34]), None);
+	}
+}
@@ -0,0 +1,118 @@
+// this file is part of arpfisch. For copyright and licensing details, see main.rs
+
+use serde::{Deserialize, Serialize};
+use std::ops::RangeInclusive;
+
+/// How incoming controller values change a fader
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum CcMode {
+	/// Absolute controller values. The fader only follows the knob once the knob has
+	/// reached the fader's current value, so the value does not jump.
+	SoftTakeover,
+	/// Relative controller values as sent by endless encoders: 1 to 63 increase the
+	/// value, 127 down to 65 decrease it.
+	Relative
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct CcBinding {
+	pub controller: u8,
+	pub mode: CcMode
+}
+
+/// Soft takeover state of a binding
+#[derive(Copy, Clone, Default, Debug)]
+pub struct Takeover {
+	/// The value the knob pointed to last time
+	last_target: Option<f32>,
+	/// The value we set the fader to last time. If the fader still has this value, the
+	/// knob is in control.
+	written: Option<f32>
+}
+
+impl CcBinding {
+	pub fn apply(
+		&self,
+		takeover: &mut Takeover,
+		cc_value: u8,
+		value: &mut f32,
+		range: RangeInclusive<f32>
+	) {
+		let span = range.end() - range.start();
+		match self.mode {
+			CcMode::SoftTakeover => {
+				let target = range.start() + cc_value as f32 / 127.0 * span;
+				let in_control = takeover.written == Some(*value)
+					|| (target - *value).abs() <= span / 127.0
+					|| takeover
+						.last_target
+						.map(|last_target| (last_target - *value) * (target - *value) <= 0.0)
+						.unwrap_or(false);
+
+				takeover.last_target = Some(target);
+				if in_control {
+					*value = target;
+					takeover.written = Some(target);
+				}
+			}
+			CcMode::Relative => {
+				let delta = if cc_value < 64 {
+					cc_value as f32
+				}
+				else {
+					cc_value as f32 - 128.0
+				};
+				*value = (*value + delta * span / 127.0).clamp(*range.start(), *range.end());
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn soft_takeover() {
+		let binding = CcBinding {
+			controller: 1,
+			mode: CcMode::SoftTakeover
+		};
+		let mut takeover = Takeover::default();
+		let mut value = 1.0;
+
+		// the knob is far below the value, so nothing happens
+		binding.apply(&mut takeover, 10, &mut value, 0.0..=2.0);
+		assert!(value == 1.0);
+		binding.apply(&mut takeover, 50, &mut value, 0.0..=2.0);
+		assert!(value == 1.0);
+
+		// the knob crosses the value and takes over
+		binding.apply(&mut takeover, 70, &mut value, 0.0..=2.0);
+		assert!(value == 70.0 / 127.0 * 2.0);
+		binding.apply(&mut takeover, 20, &mut value, 0.0..=2.0);
+		assert!(value == 20.0 / 127.0 * 2.0);
+
+		// the value was changed from somewhere else, so the knob has to catch up again
+		value = 1.5;
+		binding.apply(&mut takeover, 30, &mut value, 0.0..=2.0);
+		assert!(value == 1.5);
+	}
+
+	#[test]
+	fn relative() {
+		let binding = CcBinding {
+			controller: 1,
+			mode: CcMode::Relative
+		};
+		let mut takeover = Takeover::default();
+		let mut value = 0.0;
+
+		binding.apply(&mut takeover, 127, &mut value, 0.0..=1.0);
+		assert!(value == 0.0);
+		binding.apply(&mut takeover, 2, &mut value, 0.0..=1.0);
+		assert!(value == 2.0 / 127.0);
+		binding.apply(&mut takeover, 127, &mut value, 0.0..=1.0);
+		assert!((value - 1.0 / 127.0).abs() < 1e-6);
+	}
+}
@@ -0,0 +1,701 @@
+// this file is part of arpfisch. For copyright and licensing details, see main.rs
+
+use crate::arpeggiator::{
+	ChainEntry, ChordOrder, ClockMode, Condition, Entry, LaunchQuantization, RepeatMode,
+	VelocitySource, MAX_RATCHETS
+};
+use crate::midi::Note;
+use crate::midi_learn::{CcBinding, CcMode};
+use std::net::{SocketAddr, UdpSocket};
+use std::time::Duration;
+
+/// A parameter that can be set and queried via OSC. Indices are arpeggiator, pattern
+/// and step numbers, in this order.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Parameter {
+	ActiveArp,
+	ClockMode,
+	/// In beats per minute, for the internal clock
+	Tempo,
+	Transport,
+	Routing(usize, usize),
+	ActivePattern(usize),
+	LaunchQuantization(usize),
+	Chain(usize),
+	ChainOneShot(usize),
+	TicksPerStep(usize),
+	ChordHold(usize),
+	ChordOrder(usize),
+	VelocitySource(usize),
+	InChannel(usize),
+	OutChannel(usize),
+	Omni(usize),
+	Fill(usize),
+	Mute(usize),
+	Solo(usize),
+	RouteWhenMuted(usize),
+	Slider(usize, usize),
+	/// The controller bound to the slider
+	SliderCc(usize, usize),
+	Scale(usize),
+	/// The scale's base note, or -1 if the base note is taken from the input
+	ScaleBase(usize),
+	PatternLength(usize, usize),
+	RepeatMode(usize, usize),
+	Step(usize, usize, usize)
+}
+
+#[derive(Clone)]
+pub enum Value {
+	Int(i32),
+	Float(f32),
+	ClockMode(ClockMode),
+	RepeatMode(RepeatMode),
+	Transport(Transport),
+	LaunchQuantization(LaunchQuantization),
+	ChordOrder(ChordOrder),
+	VelocitySource(VelocitySource),
+	CcBinding(Option<CcBinding>),
+	Notes(heapless::Vec<Note, 16>),
+	Entries(heapless::Vec<Entry, 16>),
+	ChainEntries(heapless::Vec<ChainEntry, 16>)
+}
+
+/// Like the transport buttons of the config screen. Queries answer with `Continue` while
+/// playing and with `Stop` otherwise.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Transport {
+	Restart,
+	Stop,
+	Continue
+}
+
+/// Sent from the OSC thread to the realtime thread. A request without a value is a query.
+pub struct OscRequest {
+	pub parameter: Parameter,
+	pub value: Option<Value>,
+	pub sender: SocketAddr
+}
+
+/// Answer to a query, or to an invalid request (in which case `value` is `None`)
+pub struct OscReply {
+	pub parameter: Parameter,
+	pub value: Option<Value>,
+	pub receiver: SocketAddr
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Arg {
+	Int(i32),
+	Float(f32),
+	Str(String)
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct Message {
+	address: String,
+	args: Vec<Arg>
+}
+
+fn read_padded<'a>(data: &'a [u8], pos: &mut usize, len: usize) -> Option<&'a [u8]> {
+	let result = data.get(*pos..*pos + len)?;
+	*pos += len.div_ceil(4) * 4;
+	Some(result)
+}
+
+fn read_string(data: &[u8], pos: &mut usize) -> Option<String> {
+	let len = data.get(*pos..)?.iter().position(|b| *b == 0)?;
+	let bytes = read_padded(data, pos, len + 1)?;
+	std::str::from_utf8(&bytes[0..len])
+		.ok()
+		.map(|s| s.to_string())
+}
+
+fn read_u32(data: &[u8], pos: &mut usize) -> Option<u32> {
+	let bytes = read_padded(data, pos, 4)?;
+	Some(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+fn write_string(buffer: &mut Vec<u8>, string: &str) {
+	buffer.extend_from_slice(string.as_bytes());
+	buffer.push(0);
+	while !buffer.len().is_multiple_of(4) {
+		buffer.push(0);
+	}
+}
+
+/// Parses an OSC packet, which is either a single message or a bundle. Time tags of
+/// bundles are ignored, their messages are executed immediately.
+fn parse_packet(data: &[u8], messages: &mut Vec<Message>) -> Option<()> {
+	let mut pos = 0;
+	let address = read_string(data, &mut pos)?;
+
+	if address == "#bundle" {
+		pos += 8; // time tag
+		while pos < data.len() {
+			let len = read_u32(data, &mut pos)? as usize;
+			parse_packet(data.get(pos..pos + len)?, messages)?;
+			pos += len;
+		}
+		return Some(());
+	}
+
+	let mut args = Vec::new();
+	// the type tag string may be missing in old implementations
+	if pos < data.len() {
+		let type_tags = read_string(data, &mut pos)?;
+		for tag in type_tags.strip_prefix(',')?.chars() {
+			args.push(match tag {
+				'i' => Arg::Int(read_u32(data, &mut pos)? as i32),
+				'f' => Arg::Float(f32::from_bits(read_u32(data, &mut pos)?)),
+				's' => Arg::Str(read_string(data, &mut pos)?),
+				'T' => Arg::Int(1),
+				'F' => Arg::Int(0),
+				_ => return None
+			});
+		}
+	}
+
+	messages.push(Message { address, args });
+	Some(())
+}
+
+fn encode(message: &Message) -> Vec<u8> {
+	let mut buffer = Vec::new();
+	write_string(&mut buffer, &message.address);
+
+	let mut type_tags = String::from(",");
+	for arg in message.args.iter() {
+		type_tags.push(match arg {
+			Arg::Int(_) => 'i',
+			Arg::Float(_) => 'f',
+			Arg::Str(_) => 's'
+		});
+	}
+	write_string(&mut buffer, &type_tags);
+
+	for arg in message.args.iter() {
+		match arg {
+			Arg::Int(value) => buffer.extend_from_slice(&value.to_be_bytes()),
+			Arg::Float(value) => buffer.extend_from_slice(&value.to_bits().to_be_bytes()),
+			Arg::Str(value) => write_string(&mut buffer, value)
+		}
+	}
+	buffer
+}
+
+/// Parses an address like `/arp/0/pattern/1/step/2` or `/get/tempo`. Returns the
+/// parameter and whether it's a query.
+fn parse_address(address: &str) -> Option<(Parameter, bool)> {
+	let (address, query) = match address.strip_prefix("/get") {
+		Some(rest) => (rest, true),
+		None => (address, false)
+	};
+
+	let segments: Vec<&str> = address.split('/').skip(1).collect();
+	let index = |i: usize| segments.get(i).and_then(|s| s.parse::<usize>().ok());
+
+	use Parameter::*;
+	let parameter = match segments.as_slice() {
+		["active_arp"] => ActiveArp,
+		["clock_mode"] => ClockMode,
+		["tempo"] => Tempo,
+		["transport"] => Transport,
+		["routing", _, _] => Routing(index(1)?, index(2)?),
+		["arp", _, "active_pattern"] => ActivePattern(index(1)?),
+		["arp", _, "launch_quantization"] => LaunchQuantization(index(1)?),
+		["arp", _, "chain"] => Chain(index(1)?),
+		["arp", _, "chain_one_shot"] => ChainOneShot(index(1)?),
+		["arp", _, "ticks_per_step"] => TicksPerStep(index(1)?),
+		["arp", _, "chord_hold"] => ChordHold(index(1)?),
+		["arp", _, "chord_order"] => ChordOrder(index(1)?),
+		["arp", _, "velocity_source"] => VelocitySource(index(1)?),
+		["arp", _, "in_channel"] => InChannel(index(1)?),
+		["arp", _, "out_channel"] => OutChannel(index(1)?),
+		["arp", _, "omni"] => Omni(index(1)?),
+		["arp", _, "fill"] => Fill(index(1)?),
+		["arp", _, "mute"] => Mute(index(1)?),
+		["arp", _, "solo"] => Solo(index(1)?),
+		["arp", _, "route_when_muted"] => RouteWhenMuted(index(1)?),
+		["arp", _, "slider", _] => Slider(index(1)?, index(3)?),
+		["arp", _, "slider", _, "cc"] => SliderCc(index(1)?, index(3)?),
+		["arp", _, "scale"] => Scale(index(1)?),
+		["arp", _, "scale_base"] => ScaleBase(index(1)?),
+		["arp", _, "pattern", _, "length"] => PatternLength(index(1)?, index(3)?),
+		["arp", _, "pattern", _, "repeat_mode"] => RepeatMode(index(1)?, index(3)?),
+		["arp", _, "pattern", _, "step", _] => Step(index(1)?, index(3)?, index(5)?),
+		_ => return None
+	};
+	Some((parameter, query))
+}
+
+fn address(parameter: Parameter) -> String {
+	use Parameter::*;
+	match parameter {
+		ActiveArp => "/active_arp".to_string(),
+		ClockMode => "/clock_mode".to_string(),
+		Tempo => "/tempo".to_string(),
+		Transport => "/transport".to_string(),
+		Routing(from, to) => format!("/routing/{}/{}", from, to),
+		ActivePattern(arp) => format!("/arp/{}/active_pattern", arp),
+		LaunchQuantization(arp) => format!("/arp/{}/launch_quantization", arp),
+		Chain(arp) => format!("/arp/{}/chain", arp),
+		ChainOneShot(arp) => format!("/arp/{}/chain_one_shot", arp),
+		TicksPerStep(arp) => format!("/arp/{}/ticks_per_step", arp),
+		ChordHold(arp) => format!("/arp/{}/chord_hold", arp),
+		ChordOrder(arp) => format!("/arp/{}/chord_order", arp),
+		VelocitySource(arp) => format!("/arp/{}/velocity_source", arp),
+		InChannel(arp) => format!("/arp/{}/in_channel", arp),
+		OutChannel(arp) => format!("/arp/{}/out_channel", arp),
+		Omni(arp) => format!("/arp/{}/omni", arp),
+		Fill(arp) => format!("/arp/{}/fill", arp),
+		Mute(arp) => format!("/arp/{}/mute", arp),
+		Solo(arp) => format!("/arp/{}/solo", arp),
+		RouteWhenMuted(arp) => format!("/arp/{}/route_when_muted", arp),
+		Slider(arp, slider) => format!("/arp/{}/slider/{}", arp, slider),
+		SliderCc(arp, slider) => format!("/arp/{}/slider/{}/cc", arp, slider),
+		Scale(arp) => format!("/arp/{}/scale", arp),
+		ScaleBase(arp) => format!("/arp/{}/scale_base", arp),
+		PatternLength(arp, pattern) => format!("/arp/{}/pattern/{}/length", arp, pattern),
+		RepeatMode(arp, pattern) => format!("/arp/{}/pattern/{}/repeat_mode", arp, pattern),
+		Step(arp, pattern, step) => format!("/arp/{}/pattern/{}/step/{}", arp, pattern, step)
+	}
+}
+
+/// Number of arguments per entry of a step
+const ENTRY_ARGS: usize = 10;
+
+/// Parses conditions like `always`, `1:4` or `not_fill`.
+fn parse_condition(string: &str) -> Option<Condition> {
+	use Condition::*;
+	Some(match string {
+		"always" => Always,
+		"first" => First,
+		"not_first" => NotFirst,
+		"fill" => Fill,
+		"not_fill" => NotFill,
+		"previous" => Previous,
+		"not_previous" => NotPrevious,
+		_ => {
+			let (a, b) = string.split_once(':')?;
+			let (a, b) = (a.parse().ok()?, b.parse().ok()?);
+			if a < 1 || a > b {
+				return None;
+			}
+			Ratio(a, b)
+		}
+	})
+}
+
+fn condition_to_string(condition: Condition) -> String {
+	use Condition::*;
+	match condition {
+		Always => "always".to_string(),
+		Ratio(a, b) => format!("{}:{}", a, b),
+		First => "first".to_string(),
+		NotFirst => "not_first".to_string(),
+		Fill => "fill".to_string(),
+		NotFill => "not_fill".to_string(),
+		Previous => "previous".to_string(),
+		NotPrevious => "not_previous".to_string()
+	}
+}
+
+fn int_arg(arg: &Arg) -> Option<i32> {
+	match arg {
+		Arg::Int(value) => Some(*value),
+		_ => None
+	}
+}
+
+fn float_arg(arg: &Arg) -> Option<f32> {
+	match arg {
+		Arg::Int(value) => Some(*value as f32),
+		Arg::Float(value) => Some(*value),
+		_ => None
+	}
+}
+
+fn value_from_args(parameter: Parameter, args: &[Arg]) -> Option<Value> {
+	use Parameter::*;
+	match parameter {
+		Tempo | Slider(..) => match args {
+			[arg] => Some(Value::Float(float_arg(arg)?)),
+			_ => None
+		},
+		ClockMode => match args {
+			[Arg::Str(mode)] => Some(Value::ClockMode(match mode.as_str() {
+				"internal" => crate::arpeggiator::ClockMode::Internal,
+				"external" => crate::arpeggiator::ClockMode::External,
+				"auto" => crate::arpeggiator::ClockMode::Auto,
+				"jack_transport" => crate::arpeggiator::ClockMode::JackTransport,
+				_ => return None
+			})),
+			_ => None
+		},
+		Transport => match args {
+			[Arg::Str(command)] => Some(Value::Transport(match command.as_str() {
+				"restart" => self::Transport::Restart,
+				"stop" => self::Transport::Stop,
+				"continue" => self::Transport::Continue,
+				_ => return None
+			})),
+			_ => None
+		},
+		LaunchQuantization(_) => match args {
+			[Arg::Str(mode)] => Some(Value::LaunchQuantization(match mode.as_str() {
+				"immediate" => crate::arpeggiator::LaunchQuantization::Immediate,
+				"next_step" => crate::arpeggiator::LaunchQuantization::NextStep,
+				"pattern_end" => crate::arpeggiator::LaunchQuantization::PatternEnd,
+				_ => return None
+			})),
+			[Arg::Str(mode), steps] if mode == "bar" => Some(Value::LaunchQuantization(
+				crate::arpeggiator::LaunchQuantization::Bar(int_arg(steps)?.max(1) as usize)
+			)),
+			_ => None
+		},
+		ChordOrder(_) => match args {
+			[Arg::Str(order)] => Some(Value::ChordOrder(match order.as_str() {
+				"ascending" => crate::arpeggiator::ChordOrder::Ascending,
+				"descending" => crate::arpeggiator::ChordOrder::Descending,
+				"arrival" => crate::arpeggiator::ChordOrder::Arrival,
+				_ => return None
+			})),
+			_ => None
+		},
+		VelocitySource(_) => match args {
+			[Arg::Str(source)] => Some(Value::VelocitySource(match source.as_str() {
+				"step" => crate::arpeggiator::VelocitySource::Step,
+				"input" => crate::arpeggiator::VelocitySource::Input,
+				"blend" => crate::arpeggiator::VelocitySource::Blend,
+				_ => return None
+			})),
+			_ => None
+		},
+		SliderCc(..) => match args {
+			[Arg::Int(-1)] => Some(Value::CcBinding(None)),
+			[controller, Arg::Str(mode)] => Some(Value::CcBinding(Some(CcBinding {
+				controller: int_arg(controller)?.clamp(0, 127) as u8,
+				mode: match mode.as_str() {
+					"soft_takeover" => CcMode::SoftTakeover,
+					"relative" => CcMode::Relative,
+					_ => return None
+				}
+			}))),
+			_ => None
+		},
+		Chain(_) => {
+			let mut entries = heapless::Vec::new();
+			for chunk in args.chunks(2) {
+				match chunk {
+					[pattern, repeats] => entries
+						.push(ChainEntry {
+							pattern: int_arg(pattern)?.max(0) as usize,
+							repeats: int_arg(repeats)?.max(1) as usize
+						})
+						.ok()?,
+					_ => return None
+				}
+			}
+			Some(Value::ChainEntries(entries))
+		}
+		RepeatMode(..) => match args {
+			[Arg::Str(mode)] if mode == "clamp" => {
+				Some(Value::RepeatMode(crate::arpeggiator::RepeatMode::Clamp))
+			}
+			[Arg::Str(mode)] if mode == "mirror" => {
+				Some(Value::RepeatMode(crate::arpeggiator::RepeatMode::Mirror))
+			}
+			[Arg::Str(mode)] if mode == "skip" => {
+				Some(Value::RepeatMode(crate::arpeggiator::RepeatMode::Skip))
+			}
+			[Arg::Str(mode)] if mode == "random" => {
+				Some(Value::RepeatMode(crate::arpeggiator::RepeatMode::Random))
+			}
+			[Arg::Str(mode), transpose] if mode == "repeat" => Some(Value::RepeatMode(
+				crate::arpeggiator::RepeatMode::Repeat(int_arg(transpose)?)
+			)),
+			_ => None
+		},
+		Scale(_) => {
+			let mut notes = heapless::Vec::new();
+			for arg in args {
+				let note = int_arg(arg)?;
+				if !(0..12).contains(&note) {
+					return None;
+				}
+				notes.push(Note(note as u8)).ok()?;
+			}
+			Some(Value::Notes(notes))
+		}
+		Step(..) => {
+			let mut entries = heapless::Vec::new();
+			for chunk in args.chunks(ENTRY_ARGS) {
+				if chunk.len() != ENTRY_ARGS {
+					return None;
+				}
+				entries
+					.push(Entry {
+						note: int_arg(&chunk[0])? as isize,
+						len_steps: int_arg(&chunk[1])?.max(1) as u32,
+						intensity: float_arg(&chunk[2])?.clamp(0.0, 1.0),
+						transpose: int_arg(&chunk[3])?,
+						probability: float_arg(&chunk[4])?.clamp(0.0, 1.0),
+						ratchets: int_arg(&chunk[5])?.clamp(1, MAX_RATCHETS as i32) as u32,
+						ratchet_ramp: float_arg(&chunk[6])?.clamp(-1.0, 1.0),
+						delay: float_arg(&chunk[7])?.clamp(0.0, 1.0),
+						condition: match &chunk[8] {
+							Arg::Str(condition) => parse_condition(condition)?,
+							_ => return None
+						},
+						degrees: int_arg(&chunk[9])?
+					})
+					.ok()?;
+			}
+			Some(Value::Entries(entries))
+		}
+		_ => match args {
+			[arg] => Some(Value::Int(int_arg(arg)?)),
+			_ => None
+		}
+	}
+}
+
+fn args_from_value(value: &Value) -> Vec<Arg> {
+	match value {
+		Value::Int(value) => vec![Arg::Int(*value)],
+		Value::Float(value) => vec![Arg::Float(*value)],
+		Value::ClockMode(mode) => vec![Arg::Str(
+			match mode {
+				ClockMode::Internal => "internal",
+				ClockMode::External => "external",
+				ClockMode::Auto => "auto",
+				ClockMode::JackTransport => "jack_transport"
+			}
+			.to_string()
+		)],
+		Value::RepeatMode(mode) => match mode {
+			RepeatMode::Clamp => vec![Arg::Str("clamp".to_string())],
+			RepeatMode::Mirror => vec![Arg::Str("mirror".to_string())],
+			RepeatMode::Skip => vec![Arg::Str("skip".to_string())],
+			RepeatMode::Random => vec![Arg::Str("random".to_string())],
+			RepeatMode::Repeat(transpose) => {
+				vec![Arg::Str("repeat".to_string()), Arg::Int(*transpose)]
+			}
+		},
+		Value::Transport(command) => vec![Arg::Str(
+			match command {
+				Transport::Restart => "restart",
+				Transport::Stop => "stop",
+				Transport::Continue => "continue"
+			}
+			.to_string()
+		)],
+		Value::LaunchQuantization(quantization) => match quantization {
+			LaunchQuantization::Immediate => vec![Arg::Str("immediate".to_string())],
+			LaunchQuantization::NextStep => vec![Arg::Str("next_step".to_string())],
+			LaunchQuantization::PatternEnd => vec![Arg::Str("pattern_end".to_string())],
+			LaunchQuantization::Bar(steps) => {
+				vec![Arg::Str("bar".to_string()), Arg::Int(*steps as i32)]
+			}
+		},
+		Value::ChordOrder(order) => vec![Arg::Str(
+			match order {
+				ChordOrder::Ascending => "ascending",
+				ChordOrder::Descending => "descending",
+				ChordOrder::Arrival => "arrival"
+			}
+			.to_string()
+		)],
+		Value::VelocitySource(source) => vec![Arg::Str(
+			match source {
+				VelocitySource::Step => "step",
+				VelocitySource::Input => "input",
+				VelocitySource::Blend => "blend"
+			}
+			.to_string()
+		)],
+		Value::CcBinding(binding) => match binding {
+			Some(binding) => vec![
+				Arg::Int(binding.controller as i32),
+				Arg::Str(
+					match binding.mode {
+						CcMode::SoftTakeover => "soft_takeover",
+						CcMode::Relative => "relative"
+					}
+					.to_string()
+				),
+			],
+			None => vec![Arg::Int(-1)]
+		},
+		Value::ChainEntries(entries) => entries
+			.iter()
+			.flat_map(|entry| {
+				vec![
+					Arg::Int(entry.pattern as i32),
+					Arg::Int(entry.repeats as i32),
+				]
+			})
+			.collect(),
+		Value::Notes(notes) => notes.iter().map(|note| Arg::Int(note.0 as i32)).collect(),
+		Value::Entries(entries) => entries
+			.iter()
+			.flat_map(|entry| {
+				vec![
+					Arg::Int(entry.note as i32),
+					Arg::Int(entry.len_steps as i32),
+					Arg::Float(entry.intensity),
+					Arg::Int(entry.transpose),
+					Arg::Float(entry.probability),
+					Arg::Int(entry.ratchets as i32),
+					Arg::Float(entry.ratchet_ramp),
+					Arg::Float(entry.delay),
+					Arg::Str(condition_to_string(entry.condition)),
+					Arg::Int(entry.degrees),
+				]
+			})
+			.collect()
+	}
+}
+
+fn error_message(address: String) -> Message {
+	Message {
+		address: "/error".to_string(),
+		args: vec![Arg::Str(address)]
+	}
+}
+
+/// Receives OSC messages on a UDP port on localhost and passes them to the realtime
+/// thread. Every parameter can be set by sending the new value to its address, and
+/// queried by sending an empty message to the address prefixed with `/get`.
+pub struct OscServer;
+
+impl OscServer {
+	pub fn spawn(
+		port: u16,
+		mut requests: ringbuf::Producer<OscRequest>,
+		mut replies: ringbuf::Consumer<OscReply>
+	) -> std::io::Result<()> {
+		let socket = UdpSocket::bind(("127.0.0.1", port))?;
+		// we need to wake up regularly to send the replies
+		socket.set_read_timeout(Some(Duration::from_millis(10)))?;
+
+		std::thread::spawn(move || {
+			let mut buffer = [0; 65536];
+			loop {
+				if let Ok((len, sender)) = socket.recv_from(&mut buffer) {
+					let mut messages = Vec::new();
+					parse_packet(&buffer[0..len], &mut messages);
+
+					for message in messages {
+						let request =
+							parse_address(&message.address).and_then(|(parameter, query)| {
+								if query {
+									Some(OscRequest {
+										parameter,
+										value: None,
+										sender
+									})
+								}
+								else {
+									value_from_args(parameter, &message.args).map(|value| {
+										OscRequest {
+											parameter,
+											value: Some(value),
+											sender
+										}
+									})
+								}
+							});
+
+						match request {
+							Some(request) => {
+								if requests.push(request).is_err() {
+									eprintln!(
+										"OSC request queue is full, dropping {}",
+										message.address
+									);
+								}
+							}
+							None => {
+								socket
+									.send_to(&encode(&error_message(message.address)), sender)
+									.ok();
+							}
+						}
+					}
+				}
+
+				while let Some(reply) = replies.pop() {
+					let message = match reply.value {
+						Some(value) => Message {
+							address: address(reply.parameter),
+							args: args_from_value(&value)
+						},
+						None => error_message(address(reply.parameter))
+					};
+					socket.send_to(&encode(&message), reply.receiver).ok();
+				}
+			}
+		});
+
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn encode_and_parse() {
+		let message = Message {
+			address: "/arp/1/pattern/2/repeat_mode".to_string(),
+			args: vec![
+				Arg::Str("repeat".to_string()),
+				Arg::Int(-12),
+				Arg::Float(0.5),
+			]
+		};
+		let data = encode(&message);
+		assert!(data.len() % 4 == 0);
+
+		let mut messages = Vec::new();
+		parse_packet(&data, &mut messages).unwrap();
+		assert_eq!(messages, vec![message.clone()]);
+
+		let mut bundle = Vec::new();
+		write_string(&mut bundle, "#bundle");
+		bundle.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 1]);
+		for _ in 0..2 {
+			bundle.extend_from_slice(&(data.len() as u32).to_be_bytes());
+			bundle.extend_from_slice(&data);
+		}
+		let mut messages = Vec::new();
+		parse_packet(&bundle, &mut messages).unwrap();
+		assert_eq!(messages, vec![message.clone(), message]);
+
+		assert!(parse_packet(&data[0..data.len() - 4], &mut Vec::new()).is_none());
+	}
+
+	#[test]
+	fn addresses() {
+		for parameter in [
+			Parameter::ActiveArp,
+			Parameter::Tempo,
+			Parameter::Routing(0, 3),
+			Parameter::Slider(2, 4),
+			Parameter::SliderCc(2, 4),
+			Parameter::Chain(3),
+			Parameter::Step(1, 7, 31)
+		] {
+			assert_eq!(parse_address(&address(parameter)), Some((parameter, false)));
+			assert_eq!(
+				parse_address(&format!("/get{}", address(parameter))),
+				Some((parameter, true))
+			);
+		}
+		assert_eq!(parse_address("/arp/x/scale"), None);
+		assert_eq!(parse_address("/arp/0/unknown"), None);
+	}
+}
@@ -0,0 +1,29 @@
+// this file is part of arpfisch. For copyright and licensing details, see main.rs
+
+/// A small xorshift pseudo random number generator that can be used in the realtime thread.
+/// The same seed always produces the same sequence, making rendering reproducible.
+#[derive(Copy, Clone, Debug)]
+pub struct Random(u32);
+
+impl Random {
+	pub fn new(seed: u32) -> Random {
+		// the state must never be zero
+		Random(seed.wrapping_mul(0x9e3779b9) | 1)
+	}
+
+	pub fn next_u32(&mut self) -> u32 {
+		let mut x = self.0;
+		x ^= x << 13;
+		x ^= x >> 17;
+		x ^= x << 5;
+		self.0 = x;
+		x
+	}
+
+	/// Returns a number in `0.0..1.0`
+	pub fn next_f32(&mut self) -> f32 { (self.next_u32() >> 8) as f32 / (1 << 24) as f32 }
+}
+
+impl Default for Random {
+	fn default() -> Random { Random::new(1) }
+}